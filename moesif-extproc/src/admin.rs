@@ -0,0 +1,281 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::LevelFilter;
+use serde_json::{json, Value};
+
+use crate::root_context::EventRootContext;
+
+// Serves the admin endpoints until the process exits
+pub fn spawn_admin_server(addr: SocketAddr, event_context: Arc<EventRootContext>) {
+    let make_service = make_service_fn(move |_| {
+        let event_context = event_context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let event_context = event_context.clone();
+                async move { Ok::<_, Infallible>(handle_request(req, &event_context).await) }
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            log::error!("Failed to bind admin server on {}: {}", addr, e);
+            return;
+        }
+    };
+    log::info!("Starting admin server on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("Admin server error: {}", e);
+        }
+    });
+}
+
+async fn handle_request(req: Request<Body>, event_context: &EventRootContext) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(event_context),
+        (&Method::GET, "/healthz") => text_response(StatusCode::OK, "ok\n".to_string()),
+        (&Method::GET, "/readyz") => match event_context.readiness() {
+            Ok(()) => text_response(StatusCode::OK, "ready\n".to_string()),
+            Err(e) => text_response(StatusCode::SERVICE_UNAVAILABLE, format!("not ready: {}\n", e)),
+        },
+        (&Method::GET, "/config") => config(event_context),
+        (&Method::GET, "/loglevel") => {
+            text_response(StatusCode::OK, format!("{}\n", log::max_level()))
+        }
+        (&Method::PUT, "/loglevel") | (&Method::POST, "/loglevel") => set_log_level(req).await,
+        _ => text_response(StatusCode::NOT_FOUND, "Not Found\n".to_string()),
+    }
+}
+
+// The effective configuration, which serializes with the application id masked
+fn config(event_context: &EventRootContext) -> Response<Body> {
+    let env = serde_json::to_value(&event_context.config.env).unwrap_or(Value::Null);
+    let app_config = event_context
+        .app_config
+        .read()
+        .ok()
+        .and_then(|app_config| serde_json::to_value(&*app_config).ok());
+    let governance_rules = event_context
+        .governance_rules
+        .read()
+        .map(|rules| rules.rules.len())
+        .unwrap_or_default();
+
+    let body = json!({
+        "env": env,
+        "app_config": app_config,
+        "config_etag": event_context.config_etag(),
+        "rules_etag": event_context.rules_etag(),
+        "governance_rules": governance_rules,
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_default()
+}
+
+// Accepts the level either as ?level=debug or as the request body
+async fn set_log_level(req: Request<Body>) -> Response<Body> {
+    let query_level = req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("level="))
+            .map(|level| level.to_string())
+    });
+    let level = match query_level {
+        Some(level) => level,
+        None => match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => String::from_utf8_lossy(&body).trim().to_string(),
+            Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("{}\n", e)),
+        },
+    };
+
+    match LevelFilter::from_str(&level) {
+        Ok(level) => {
+            log::set_max_level(level);
+            log::warn!("Log level changed to {} through the admin server", level);
+            text_response(StatusCode::OK, format!("{}\n", level))
+        }
+        Err(_) => text_response(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid log level {:?}, expected off, error, warn, info, debug or trace\n", level),
+        ),
+    }
+}
+
+fn metrics(event_context: &EventRootContext) -> Response<Body> {
+    let metrics = &event_context.metrics;
+    // Gauges are sampled at scrape time
+    metrics
+        .active_streams
+        .set(event_context.active_streams.load(Ordering::SeqCst) as i64);
+    metrics.queue_depth.set(event_context.queue_depth() as i64);
+
+    match metrics.encode() {
+        Ok(body) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap_or_default(),
+        Err(e) => {
+            log::error!("Failed to encode metrics: {:?}", e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e))
+        }
+    }
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(body))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::event::Event;
+    use crate::grpc_service::MoesifGlooExtProcGrpcService;
+    use crate::test_utils::{
+        request_headers, response_headers, run_stream, test_env, wait_for, MockResponse,
+        MockServer,
+    };
+
+    fn test_context(server: &MockServer) -> EventRootContext {
+        EventRootContext::new(Config {
+            env: test_env(
+                &server.base_uri,
+                json!({"batch_max_wait": 20, "retry_max_attempts": 1}),
+            ),
+        })
+    }
+
+    async fn request(
+        event_context: &EventRootContext,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = handle_request(req, event_context).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn readyz_follows_the_last_upload() {
+        let server = MockServer::start();
+        server.respond("/v1/events/batch", MockResponse::status(500));
+        let event_context = test_context(&server);
+        assert!(event_context.wait_until_ready().await);
+        assert_eq!(
+            request(&event_context, Method::GET, "/readyz", "").await,
+            (StatusCode::OK, "ready\n".to_string())
+        );
+
+        event_context.push_event(Event::new()).await;
+        wait_for("the failed upload", || event_context.readiness().is_err()).await;
+        let (status, body) = request(&event_context, Method::GET, "/readyz", "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("no successful upload"), "{}", body);
+
+        server.respond("/v1/events/batch", MockResponse::ok(""));
+        event_context.push_event(Event::new()).await;
+        wait_for("the next upload", || event_context.readiness().is_ok()).await;
+        assert_eq!(
+            request(&event_context, Method::GET, "/readyz", "").await,
+            (StatusCode::OK, "ready\n".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn config_masks_the_application_id() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/config",
+            MockResponse::ok(r#"{"sample_rate":50}"#).header("X-Moesif-Config-Etag", "etag-1"),
+        );
+        let event_context = test_context(&server);
+        wait_for("the app config", || event_context.config_etag().is_some()).await;
+
+        let (status, body) = request(&event_context, Method::GET, "/config", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("test-application-id"));
+        let config: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(config["env"]["moesif_application_id"], "****n-id");
+        assert_eq!(config["env"]["batch_max_wait"], 20);
+        assert_eq!(config["app_config"]["sample_rate"], 50);
+        assert_eq!(config["config_etag"], "etag-1");
+        // Logged at startup
+        let logged = format!("{:?}", event_context.config);
+        assert!(logged.contains(r#"moesif_application_id: "****n-id""#), "{}", logged);
+    }
+
+    #[tokio::test]
+    async fn metrics_expose_the_event_pipeline() {
+        let server = MockServer::start();
+        let env = test_env(&server.base_uri, json!({"batch_max_wait": 20}));
+        let service = MoesifGlooExtProcGrpcService::new(Config { env }).unwrap();
+        let event_context = service.event_context();
+
+        run_stream(
+            service,
+            vec![
+                request_headers(&[(":method", "GET"), (":path", "/items")]),
+                response_headers(&[(":status", "200")]),
+            ],
+        )
+        .await;
+        let uploads = event_context
+            .metrics
+            .api_responses
+            .with_label_values(&["/v1/events/batch", "200"]);
+        wait_for("the batch upload", || uploads.get() == 1).await;
+
+        let (status, body) = request(&event_context, Method::GET, "/metrics", "").await;
+        assert_eq!(status, StatusCode::OK);
+        for series in [
+            "moesif_events_enqueued_total 1",
+            "moesif_queue_depth 0",
+            r#"moesif_api_responses_total{path="/v1/events/batch",status="200"} 1"#,
+        ] {
+            assert!(body.lines().any(|line| line == series), "{} missing from:\n{}", series, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_the_log_level() {
+        let server = MockServer::start();
+        let event_context = test_context(&server);
+        let max_level = log::max_level();
+
+        let response = request(&event_context, Method::PUT, "/loglevel", "debug\n").await;
+        assert_eq!(response, (StatusCode::OK, "DEBUG\n".to_string()));
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+        let response = request(&event_context, Method::PUT, "/loglevel?level=trace", "").await;
+        assert_eq!(response, (StatusCode::OK, "TRACE\n".to_string()));
+        assert_eq!(
+            request(&event_context, Method::GET, "/loglevel", "").await,
+            (StatusCode::OK, "TRACE\n".to_string())
+        );
+
+        let (status, _) = request(&event_context, Method::PUT, "/loglevel", "verbose").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+        log::set_max_level(max_level);
+    }
+}
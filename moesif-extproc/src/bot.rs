@@ -0,0 +1,157 @@
+use std::fs;
+
+use log::{error, info};
+use serde_json::{Map, Value};
+
+use crate::event::Event;
+
+// Lowercase user agent fragments of well known crawlers and bots
+const DEFAULT_BOT_PATTERNS: &[&str] = &[
+    "googlebot",
+    "adsbot-google",
+    "mediapartners-google",
+    "bingbot",
+    "bingpreview",
+    "slurp",
+    "duckduckbot",
+    "baiduspider",
+    "yandexbot",
+    "sogou",
+    "exabot",
+    "facebot",
+    "facebookexternalhit",
+    "ia_archiver",
+    "applebot",
+    "twitterbot",
+    "linkedinbot",
+    "slackbot",
+    "discordbot",
+    "telegrambot",
+    "petalbot",
+    "semrushbot",
+    "ahrefsbot",
+    "mj12bot",
+    "dotbot",
+    "bytespider",
+    "gptbot",
+    "ccbot",
+    "crawler",
+    "spider",
+];
+
+pub struct BotClassifier {
+    patterns: Vec<String>,
+}
+
+impl BotClassifier {
+    pub fn new(patterns_file: Option<&str>) -> Self {
+        let patterns = match patterns_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => {
+                    let patterns: Vec<String> = contents
+                        .lines()
+                        .map(|line| line.trim().to_lowercase())
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .collect();
+                    info!("Loaded {} bot patterns from {}", patterns.len(), path);
+                    patterns
+                }
+                Err(e) => {
+                    error!("Failed to read bot patterns from {}: {}, using defaults.", path, e);
+                    default_patterns()
+                }
+            },
+            None => default_patterns(),
+        };
+        BotClassifier { patterns }
+    }
+
+    pub fn classify(&self, event: &Event) -> Option<String> {
+        let user_agent = event.request.headers.get("user-agent")?.to_lowercase();
+        self.patterns
+            .iter()
+            .find(|pattern| user_agent.contains(pattern.as_str()))
+            .cloned()
+    }
+}
+
+fn default_patterns() -> Vec<String> {
+    DEFAULT_BOT_PATTERNS.iter().map(|p| p.to_string()).collect()
+}
+
+pub fn tag_event(event: &mut Event, bot_name: &str) {
+    if !event.metadata.is_object() {
+        event.metadata = Value::Object(Map::new());
+    }
+    if let Some(metadata) = event.metadata.as_object_mut() {
+        metadata.insert("is_bot".to_string(), Value::Bool(true));
+        metadata.insert("bot_name".to_string(), Value::String(bot_name.to_string()));
+    }
+}
+
+pub fn get_bot_name(event: &Event) -> Option<&str> {
+    event.metadata.get("bot_name").and_then(|name| name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use serde_json::json;
+
+    fn event(user_agent: &str) -> Event {
+        let mut event = Event::new();
+        event
+            .request
+            .headers
+            .insert("user-agent".to_string(), user_agent.to_string());
+        event
+    }
+
+    #[test]
+    fn classifies_user_agents_matching_a_default_pattern() {
+        let classifier = BotClassifier::new(None);
+
+        let googlebot = event("Mozilla/5.0 (compatible; Googlebot/2.1)");
+        assert_eq!(classifier.classify(&googlebot).as_deref(), Some("googlebot"));
+        assert_eq!(classifier.classify(&event("Mozilla/5.0 (X11; Linux x86_64)")), None);
+        assert_eq!(classifier.classify(&Event::new()), None);
+    }
+
+    #[test]
+    fn patterns_file_replaces_the_defaults() {
+        let dir = TempDir::new();
+        let path = format!("{}/bots.txt", dir.path());
+        fs::write(&path, "# Internal monitoring\n\n  Acme-Monitor  \n# googlebot\n").unwrap();
+        let classifier = BotClassifier::new(Some(&path));
+
+        let monitor = event("acme-monitor/1.0");
+        assert_eq!(classifier.classify(&monitor).as_deref(), Some("acme-monitor"));
+        assert_eq!(classifier.classify(&event("Googlebot/2.1")), None);
+    }
+
+    #[test]
+    fn missing_patterns_file_falls_back_to_the_defaults() {
+        let classifier = BotClassifier::new(Some("/nonexistent/bots.txt"));
+
+        assert_eq!(classifier.classify(&event("bingbot/2.0")).as_deref(), Some("bingbot"));
+    }
+
+    #[test]
+    fn tag_event_keeps_existing_metadata() {
+        let mut tagged = Event::new();
+        tagged.metadata = json!({"tenant": "acme"});
+        tag_event(&mut tagged, "googlebot");
+
+        assert_eq!(
+            tagged.metadata,
+            json!({"tenant": "acme", "is_bot": true, "bot_name": "googlebot"})
+        );
+        assert_eq!(get_bot_name(&tagged), Some("googlebot"));
+
+        let mut untagged = Event::new();
+        assert_eq!(get_bot_name(&untagged), None);
+        tag_event(&mut untagged, "bingbot");
+        assert_eq!(get_bot_name(&untagged), Some("bingbot"));
+    }
+}
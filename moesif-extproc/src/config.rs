@@ -1,23 +1,37 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::logging::LogFormat;
+use crate::redaction::{compile_header_pattern, validate_body_field, DEFAULT_HEADER_DENY_LIST};
+use crate::utils::mask_secret;
 
 #[derive(Debug, Default, Clone)]
 pub struct Config {
     pub env: EnvConfig,
 }
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct EnvConfig {
-    pub moesif_application_id: String,
+    pub moesif_application_id: ApplicationId,
     // use serde to make these values to_lowercase
     pub user_id_header: Option<String>,
     pub company_id_header: Option<String>,
     #[serde(default = "default_batch_max_size")]
     pub batch_max_size: usize,
+    #[serde(default = "default_batch_max_bytes")]
+    pub batch_max_bytes: usize,
     #[serde(default = "default_batch_max_wait")]
     pub batch_max_wait: u64,
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
     #[serde(default = "default_queue_max_size")]
     pub queue_max_size: usize,
+    #[serde(default)]
+    pub overflow_policy: OverflowPolicy,
     #[serde(default = "default_grpc_processing_queue_size")]
     pub grpc_processing_queue_size: usize,
     #[serde(default = "default_base_uri")]
@@ -27,6 +41,115 @@ pub struct EnvConfig {
     #[serde(default = "connection_timeout")]
     pub connection_timeout: u64,
     pub rust_log: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub log_bodies: bool,
+    #[serde(default = "default_ip_block_status")]
+    pub ip_block_status: i32,
+    #[serde(default = "default_ip_block_body")]
+    pub ip_block_body: String,
+    #[serde(default)]
+    pub xff_num_trusted_hops: usize,
+    pub bot_patterns_file: Option<String>,
+    #[serde(default)]
+    pub skip_bot_traffic: bool,
+    #[serde(default = "default_bot_block_status")]
+    pub bot_block_status: i32,
+    #[serde(default = "default_bot_block_body")]
+    pub bot_block_body: String,
+    #[serde(default = "default_header_deny_list")]
+    pub header_deny_list: Vec<String>,
+    #[serde(default)]
+    pub header_deny_patterns: Vec<String>,
+    #[serde(default)]
+    pub header_allow_list: Vec<String>,
+    #[serde(default)]
+    pub header_redaction_mode: HeaderRedactionMode,
+    #[serde(default)]
+    pub request_body_mask_fields: Vec<String>,
+    #[serde(default)]
+    pub response_body_mask_fields: Vec<String>,
+    #[serde(default)]
+    pub body_mask_mode: BodyMaskMode,
+    #[serde(default)]
+    pub enable_transaction_id: bool,
+    #[serde(default = "default_transaction_id_header")]
+    pub transaction_id_header: String,
+    #[serde(default = "default_grpc_address")]
+    pub grpc_address: String,
+    #[serde(default = "default_grpc_port")]
+    pub grpc_port: u16,
+    pub grpc_uds_path: Option<String>,
+    #[serde(default = "default_admin_address")]
+    pub admin_address: String,
+    pub admin_port: Option<u16>,
+    pub otel_exporter_otlp_endpoint: Option<String>,
+    #[serde(default = "default_otel_service_name")]
+    pub otel_service_name: String,
+    #[serde(default = "default_readiness_upload_max_age")]
+    pub readiness_upload_max_age: u64,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_client_ca_file: Option<String>,
+    #[serde(default)]
+    pub tls_require_client_cert: bool,
+    #[serde(default = "default_tls_reload_interval")]
+    pub tls_reload_interval: u64,
+    #[serde(default = "default_tls_handshake_timeout")]
+    pub tls_handshake_timeout: u64,
+    #[serde(default = "default_shutdown_drain_period")]
+    pub shutdown_drain_period: u64,
+    #[serde(default = "default_shutdown_stream_timeout")]
+    pub shutdown_stream_timeout: u64,
+    #[serde(default = "default_shutdown_flush_timeout")]
+    pub shutdown_flush_timeout: u64,
+    #[serde(default = "default_retry_max_attempts")]
+    pub retry_max_attempts: u32,
+    #[serde(default = "default_retry_base_backoff")]
+    pub retry_base_backoff: u64,
+    #[serde(default = "default_retry_max_backoff")]
+    pub retry_max_backoff: u64,
+    #[serde(default = "default_retry_after_max")]
+    pub retry_after_max: u64,
+    #[serde(default = "default_retry_jitter")]
+    pub retry_jitter: bool,
+    #[serde(default)]
+    pub gzip_enabled: bool,
+    #[serde(default = "default_gzip_level")]
+    pub gzip_level: u32,
+    #[serde(default = "default_gzip_min_size")]
+    pub gzip_min_size: usize,
+    pub spool_dir: Option<String>,
+    #[serde(default = "default_spool_segment_max_bytes")]
+    pub spool_segment_max_bytes: u64,
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64,
+    #[serde(default = "default_spool_replay_interval")]
+    pub spool_replay_interval: u64,
+}
+
+// The Moesif application id, masked wherever the config is logged or served
+#[derive(Default, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct ApplicationId(String);
+
+impl ApplicationId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ApplicationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", mask_secret(&self.0))
+    }
+}
+
+impl Serialize for ApplicationId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&mask_secret(&self.0))
+    }
 }
 
 fn default_batch_max_size() -> usize {
@@ -37,6 +160,14 @@ fn default_batch_max_wait() -> u64 {
     2000
 }
 
+fn default_batch_max_bytes() -> usize {
+    5 * 1024 * 1024
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    4
+}
+
 fn default_queue_max_size() -> usize {
     10000
 }
@@ -57,6 +188,140 @@ fn connection_timeout() -> u64 {
     5000
 }
 
+fn default_ip_block_status() -> i32 {
+    403
+}
+
+fn default_ip_block_body() -> String {
+    r#"{"error":"Your IP address is blocked."}"#.to_string()
+}
+
+fn default_bot_block_status() -> i32 {
+    403
+}
+
+fn default_bot_block_body() -> String {
+    r#"{"error":"Bot traffic is not allowed."}"#.to_string()
+}
+
+fn default_header_deny_list() -> Vec<String> {
+    DEFAULT_HEADER_DENY_LIST.iter().map(|name| name.to_string()).collect()
+}
+
+fn default_transaction_id_header() -> String {
+    "x-moesif-transaction-id".to_string()
+}
+
+fn default_grpc_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_grpc_port() -> u16 {
+    50051
+}
+
+fn default_admin_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_otel_service_name() -> String {
+    "moesif-extproc-plugin".to_string()
+}
+
+fn default_readiness_upload_max_age() -> u64 {
+    300000
+}
+
+fn default_tls_reload_interval() -> u64 {
+    10000
+}
+
+fn default_tls_handshake_timeout() -> u64 {
+    10000
+}
+
+fn default_shutdown_drain_period() -> u64 {
+    10000
+}
+
+// With the drain period and flush timeout this stays under Kubernetes' default 30s grace period
+fn default_shutdown_stream_timeout() -> u64 {
+    5000
+}
+
+fn default_shutdown_flush_timeout() -> u64 {
+    10000
+}
+
+fn default_retry_max_attempts() -> u32 {
+    4
+}
+
+fn default_retry_base_backoff() -> u64 {
+    500
+}
+
+fn default_retry_max_backoff() -> u64 {
+    10000
+}
+
+fn default_retry_after_max() -> u64 {
+    300000
+}
+
+fn default_retry_jitter() -> bool {
+    true
+}
+
+fn default_gzip_level() -> u32 {
+    6
+}
+
+fn default_gzip_min_size() -> usize {
+    1024
+}
+
+fn default_spool_segment_max_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_spool_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_spool_replay_interval() -> u64 {
+    60000
+}
+
+// What push_event does when the event queue is full
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    #[default]
+    Block,
+    DropNewest,
+    DropOldest,
+}
+
+// How push_event redacts denied headers
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderRedactionMode {
+    Remove,
+    #[default]
+    Mask,
+    Sha256,
+}
+
+// How push_event masks the configured body fields
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyMaskMode {
+    Remove,
+    #[default]
+    Mask,
+}
+
 impl EnvConfig {
     pub fn new() -> Self {
         let mut env = match envy::from_env::<EnvConfig>() {
@@ -74,15 +339,22 @@ impl EnvConfig {
     }
 
     pub fn validate(&self) -> Result<(), String> {
-        if self.moesif_application_id.is_empty() {
+        if self.moesif_application_id.as_str().is_empty() {
             return Err("moesif_application_id cannot be empty.".to_string());
         }
         if self.batch_max_size == 0 {
             return Err("batch_max_size cannot be zero.".to_string());
         }
+        // Leaves room for an event with truncated bodies
+        if self.batch_max_bytes < 4096 {
+            return Err("batch_max_bytes must be at least 4096.".to_string());
+        }
         if self.batch_max_wait == 0 {
             return Err("batch_max_wait cannot be zero.".to_string());
         }
+        if self.max_concurrent_uploads == 0 {
+            return Err("max_concurrent_uploads cannot be zero.".to_string());
+        }
         if self.queue_max_size == 0 {
             return Err("queue_max_size cannot be zero.".to_string());
         }
@@ -95,17 +367,134 @@ impl EnvConfig {
         if self.base_uri.is_empty() {
             return Err("base_uri cannot be empty.".to_string());
         }
+        if !(100..=599).contains(&self.ip_block_status) {
+            return Err("ip_block_status must be a valid HTTP status code.".to_string());
+        }
+        if !(100..=599).contains(&self.bot_block_status) {
+            return Err("bot_block_status must be a valid HTTP status code.".to_string());
+        }
+        for pattern in &self.header_deny_patterns {
+            compile_header_pattern(pattern)?;
+        }
+        for field in self.request_body_mask_fields.iter().chain(&self.response_body_mask_fields) {
+            validate_body_field(field)?;
+        }
+        if self.enable_transaction_id && self.transaction_id_header.is_empty() {
+            return Err("transaction_id_header cannot be empty.".to_string());
+        }
+        if self.grpc_uds_path.is_none() {
+            self.grpc_socket_addr()?;
+        }
+        if self.admin_port.is_some() {
+            self.admin_socket_addr()?;
+        }
+        if self.otel_exporter_otlp_endpoint.as_deref() == Some("") {
+            return Err("otel_exporter_otlp_endpoint cannot be empty.".to_string());
+        }
+        if self.grpc_uds_path.as_deref() == Some("") {
+            return Err("grpc_uds_path cannot be empty.".to_string());
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err("tls_cert_file and tls_key_file must be set together.".to_string());
+        }
+        if self.tls_client_ca_file.is_some() && !self.tls_enabled() {
+            return Err("tls_client_ca_file requires tls_cert_file and tls_key_file.".to_string());
+        }
+        if self.tls_require_client_cert && self.tls_client_ca_file.is_none() {
+            return Err("tls_require_client_cert requires tls_client_ca_file.".to_string());
+        }
+        if self.tls_enabled() && self.grpc_uds_path.is_some() {
+            return Err("TLS is not supported with grpc_uds_path.".to_string());
+        }
+        if self.tls_reload_interval == 0 {
+            return Err("tls_reload_interval cannot be zero.".to_string());
+        }
+        if self.tls_handshake_timeout == 0 {
+            return Err("tls_handshake_timeout cannot be zero.".to_string());
+        }
+        if self.shutdown_flush_timeout == 0 {
+            return Err("shutdown_flush_timeout cannot be zero.".to_string());
+        }
+        if self.retry_max_attempts == 0 {
+            return Err("retry_max_attempts cannot be zero.".to_string());
+        }
+        if self.retry_base_backoff == 0 {
+            return Err("retry_base_backoff cannot be zero.".to_string());
+        }
+        if self.retry_max_backoff < self.retry_base_backoff {
+            return Err("retry_max_backoff cannot be less than retry_base_backoff.".to_string());
+        }
+        if self.gzip_level > 9 {
+            return Err("gzip_level must be between 0 and 9.".to_string());
+        }
+        if self.spool_dir.as_deref() == Some("") {
+            return Err("spool_dir cannot be empty.".to_string());
+        }
+        if self.spool_segment_max_bytes == 0 {
+            return Err("spool_segment_max_bytes cannot be zero.".to_string());
+        }
+        if self.spool_max_bytes < self.spool_segment_max_bytes {
+            return Err("spool_max_bytes cannot be less than spool_segment_max_bytes.".to_string());
+        }
+        if self.spool_replay_interval == 0 {
+            return Err("spool_replay_interval cannot be zero.".to_string());
+        }
         Ok(())
     }
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert_file.is_some() && self.tls_key_file.is_some()
+    }
+
+    pub fn grpc_socket_addr(&self) -> Result<SocketAddr, String> {
+        parse_socket_addr(&self.grpc_address, self.grpc_port)
+            .map_err(|e| format!("Invalid grpc_address {}: {}", self.grpc_address, e))
+    }
+
+    // None when the admin server is disabled
+    pub fn admin_socket_addr(&self) -> Result<Option<SocketAddr>, String> {
+        self.admin_port
+            .map(|port| {
+                parse_socket_addr(&self.admin_address, port)
+                    .map_err(|e| format!("Invalid admin_address {}: {}", self.admin_address, e))
+            })
+            .transpose()
+    }
+
     fn post_process(&mut self) {
         self.user_id_header = self.user_id_header.as_ref().map(|s| s.to_lowercase());
         self.company_id_header = self.company_id_header.as_ref().map(|s| s.to_lowercase());
+        self.transaction_id_header = self.transaction_id_header.to_lowercase();
+        // An empty list is read as a single empty name
+        for list in [&mut self.header_deny_list, &mut self.header_allow_list] {
+            *list = list
+                .iter()
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+        // JSONPath keys are case sensitive, so body fields are only trimmed
+        for list in [
+            &mut self.header_deny_patterns,
+            &mut self.request_body_mask_fields,
+            &mut self.response_body_mask_fields,
+        ] {
+            *list = list
+                .iter()
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+                .collect();
+        }
     }
 }
 
+// Accepts IPv6 addresses with or without brackets, e.g. "::" or "[::1]"
+fn parse_socket_addr(address: &str, port: u16) -> Result<SocketAddr, std::net::AddrParseError> {
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    address.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port))
+}
 
-//TODO load dynamic from config api on update
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct AppConfigResponse {
     pub org_id: String,
     pub app_id: String,
@@ -121,20 +510,113 @@ pub struct AppConfigResponse {
     pub e_tag: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+impl AppConfigResponse {
+    pub fn compile_regexes(&mut self) {
+        for rule in &mut self.regex_config {
+            rule.conditions.iter_mut().for_each(RegexCondition::compile);
+        }
+    }
+}
+
+impl Default for AppConfigResponse {
+    fn default() -> Self {
+        AppConfigResponse {
+            org_id: String::new(),
+            app_id: String::new(),
+            sample_rate: 100,
+            block_bot_traffic: false,
+            user_sample_rate: HashMap::new(),
+            company_sample_rate: HashMap::new(),
+            user_rules: HashMap::new(),
+            company_rules: HashMap::new(),
+            ip_addresses_blocked_by_name: HashMap::new(),
+            regex_config: Vec::new(),
+            billing_config_jsons: HashMap::new(),
+            e_tag: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct EntityRuleValues {
     pub rules: String,
     pub values: Option<HashMap<String, String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct RegexRule {
     pub conditions: Vec<RegexCondition>,
     pub sample_rate: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct RegexCondition {
     pub path: String,
     pub value: String,
+    // Compiled from value when the config is loaded, None if it is not a valid regex
+    #[serde(skip)]
+    pub regex: Option<Regex>,
+}
+
+impl RegexCondition {
+    pub fn compile(&mut self) {
+        self.regex = match Regex::new(&self.value) {
+            Ok(regex) => Some(regex),
+            Err(e) => {
+                log::warn!("Invalid regex {:?} for {}, it never matches: {}", self.value, self.path, e);
+                None
+            }
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct GovernanceRule {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rule_type: String,
+    pub block: bool,
+    pub applied_to: Option<String>,
+    pub applied_to_unidentified: bool,
+    pub regex_config: Vec<GovernanceRegexRule>,
+    pub variables: Vec<GovernanceRuleVariable>,
+    pub response: GovernanceRuleResponse,
+}
+
+impl GovernanceRule {
+    pub fn compile_regexes(&mut self) {
+        for regex_rule in &mut self.regex_config {
+            regex_rule.conditions.iter_mut().for_each(RegexCondition::compile);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct GovernanceRegexRule {
+    pub conditions: Vec<RegexCondition>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct GovernanceRuleVariable {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct GovernanceRuleResponse {
+    pub status: i32,
+    pub headers: HashMap<String, String>,
+    pub body: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GovernanceRules {
+    pub rules: Vec<GovernanceRule>,
+    pub e_tag: Option<String>,
 }
\ No newline at end of file
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use base64::Engine;
 use envoy_ext_proc_proto::envoy::config::core::v3::HeaderMap;
-use log::{info, trace};
+use log::trace;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use chrono::Utc;
+use uuid::Uuid;
 
 
 use std::net::IpAddr;
@@ -22,6 +23,9 @@ pub struct RequestInfo {
     pub api_version: Option<String>,
     pub ip_address: Option<String>,
     pub body: Value,
+    // Size of the captured body before it was encoded, not sent to Moesif
+    #[serde(skip)]
+    pub body_size: usize,
 }
 
 impl RequestInfo {
@@ -54,6 +58,7 @@ impl RequestInfo {
     pub fn set_body(&mut self, body_bytes: &[u8]) {
         if !body_bytes.is_empty() {
             (self.body, self.transfer_encoding) = encode_body(body_bytes);
+            self.body_size = body_bytes.len();
         }
     }
 }
@@ -66,6 +71,8 @@ pub struct ResponseInfo {
     pub ip_address: Option<String>,
     pub body: Value,
     pub transfer_encoding: Option<String>, // Ensure this is included
+    #[serde(skip)]
+    pub body_size: usize,
 }
 
 
@@ -94,6 +101,7 @@ impl ResponseInfo {
     pub fn set_body(&mut self, body_bytes: &[u8]) {
         if !body_bytes.is_empty() {
             (self.body, self.transfer_encoding) = encode_body(body_bytes);
+            self.body_size = body_bytes.len();
         }
     }
 }
@@ -108,6 +116,8 @@ pub struct Event {
     pub direction: String,
     pub session_token: Option<String>,
     pub blocked_by: Option<String>,
+    pub weight: Option<i32>,
+    pub transaction_id: Option<String>,
 }
 
 impl Event {
@@ -133,6 +143,34 @@ impl Event {
             }
         }
     }
+
+    // Replaces the bodies that were captured with a marker that records their size as captured
+    pub fn truncate_bodies(&mut self) {
+        if !self.request.body.is_null() {
+            self.request.body = truncated_body(self.request.body_size);
+            self.request.transfer_encoding = None;
+        }
+        if let Some(response) = self.response.as_mut().filter(|r| !r.body.is_null()) {
+            response.body = truncated_body(response.body_size);
+            response.transfer_encoding = None;
+        }
+    }
+
+    // Reuses the transaction id sent by the client, otherwise generates a new one.
+    // Returns true when the id was generated and still has to be added upstream.
+    pub fn set_transaction_id(&mut self, header_name: &str) -> bool {
+        if let Some(transaction_id) = self.request.headers.get(header_name) {
+            self.transaction_id = Some(transaction_id.clone());
+            return false;
+        }
+        let transaction_id = Uuid::new_v4().to_string();
+        trace!("Generated transaction_id: {}", transaction_id);
+        self.request
+            .headers
+            .insert(header_name.to_string(), transaction_id.clone());
+        self.transaction_id = Some(transaction_id);
+        true
+    }
 }
 
 pub fn get_client_ip(headers: &HashMap<String, String>) -> Option<String> {
@@ -164,6 +202,37 @@ pub fn get_client_ip(headers: &HashMap<String, String>) -> Option<String> {
     None
 }
 
+// The client address as Envoy saw it, for decisions a client must not be able to influence.
+// Envoy sets x-envoy-external-address itself and appends the downstream address to
+// x-forwarded-for, skipping the entries added by the trusted hops in front of it.
+pub fn get_envoy_client_ip(
+    headers: &HashMap<String, String>,
+    xff_num_trusted_hops: usize,
+) -> Option<String> {
+    let is_ip = |ip: &&str| IpAddr::from_str(ip).is_ok();
+    if let Some(ip) = headers
+        .get("x-envoy-external-address")
+        .map(|value| value.trim())
+        .filter(is_ip)
+    {
+        return Some(ip.to_string());
+    }
+    headers
+        .get("x-forwarded-for")?
+        .rsplit(',')
+        .nth(xff_num_trusted_hops)
+        .map(str::trim)
+        .filter(is_ip)
+        .map(str::to_string)
+}
+
+fn truncated_body(original_size: usize) -> Value {
+    serde_json::json!({
+        "msg": "Body was truncated because the event exceeded batch_max_bytes",
+        "original_size": original_size,
+    })
+}
+
 fn encode_body(body_bytes: &[u8]) -> (Value, Option<String>) {
     match serde_json::from_slice::<Value>(body_bytes) {
         Ok(json_value) => {
@@ -197,4 +266,65 @@ pub fn header_list_to_map(header_map: Option<HeaderMap>) -> HashMap<String, Stri
     }
 
     map
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn envoy_client_ip_ignores_client_supplied_headers() {
+        let spoofed = headers(&[
+            ("x-client-ip", "192.0.2.1"),
+            ("x-forwarded-for", "192.0.2.1, 198.51.100.4, 203.0.113.7"),
+        ]);
+        assert_eq!(get_client_ip(&spoofed).as_deref(), Some("192.0.2.1"));
+        assert_eq!(get_envoy_client_ip(&spoofed, 0).as_deref(), Some("203.0.113.7"));
+        assert_eq!(get_envoy_client_ip(&spoofed, 1).as_deref(), Some("198.51.100.4"));
+        assert_eq!(get_envoy_client_ip(&spoofed, 3), None);
+
+        let external = headers(&[
+            ("x-envoy-external-address", "203.0.113.9"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(get_envoy_client_ip(&external, 0).as_deref(), Some("203.0.113.9"));
+        assert_eq!(get_envoy_client_ip(&headers(&[("x-client-ip", "192.0.2.1")]), 0), None);
+    }
+
+    #[test]
+    fn truncate_bodies_replaces_captured_bodies_with_their_size() {
+        let mut event = Event::new();
+        event.request.set_body(b"\x00\x01binary");
+        let mut response = ResponseInfo::new();
+        response.set_body(br#"{"items": [1, 2, 3]}"#);
+        event.response = Some(response);
+
+        event.truncate_bodies();
+
+        assert_eq!(event.request.body["original_size"], json!(8));
+        assert_eq!(event.request.transfer_encoding, None);
+        let response = event.response.unwrap();
+        assert_eq!(response.body["original_size"], json!(r#"{"items": [1, 2, 3]}"#.len()));
+        assert!(response.body["msg"].as_str().unwrap().contains("batch_max_bytes"));
+    }
+
+    #[test]
+    fn truncate_bodies_leaves_missing_bodies_null() {
+        let mut event = Event::new();
+        event.response = Some(ResponseInfo::new());
+        event.response.as_mut().unwrap().set_body(b"not json");
+
+        event.truncate_bodies();
+
+        assert!(event.request.body.is_null());
+        assert!(event.response.unwrap().body.is_object());
+    }
+}
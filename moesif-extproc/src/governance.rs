@@ -0,0 +1,465 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use log::trace;
+use serde_json::Value;
+
+use crate::config::{AppConfigResponse, EntityRuleValues, EnvConfig, GovernanceRule};
+use crate::event::Event;
+use crate::sampling::{get_field_value, matches_conditions};
+
+// Envoy rejects an ImmediateResponse without a valid status, so rules without one use this
+const DEFAULT_RULE_BLOCK_STATUS: i32 = 403;
+
+#[derive(Debug, Clone)]
+pub struct BlockResponse {
+    pub blocked_by: String,
+    pub status: i32,
+    pub headers: HashMap<String, String>,
+    pub body: Value,
+}
+
+// Entity rules are more specific than regex rules, so user then company rules are checked first
+pub fn evaluate(
+    app_config: &AppConfigResponse,
+    rules: &[GovernanceRule],
+    event: &Event,
+) -> Option<BlockResponse> {
+    evaluate_entity_rules(rules, "user", event, &event.user_id, &app_config.user_rules)
+        .or_else(|| {
+            evaluate_entity_rules(
+                rules,
+                "company",
+                event,
+                &event.company_id,
+                &app_config.company_rules,
+            )
+        })
+        .or_else(|| evaluate_regex_rules(rules, event))
+}
+
+// Blocked entries are keyed by a single IP or a CIDR range and map to the rule name. When entries
+// overlap, the longest prefix wins, so a single IP takes precedence over the range it is in.
+pub fn evaluate_blocked_ip(
+    blocked_ips: &HashMap<String, String>,
+    ip_address: &str,
+    env: &EnvConfig,
+) -> Option<BlockResponse> {
+    let ip: IpAddr = ip_address.parse().ok()?;
+    blocked_ips
+        .iter()
+        .filter_map(|(blocked, rule_name)| {
+            let network = match blocked.trim().parse::<IpNet>() {
+                Ok(network) => network,
+                Err(_) => IpNet::from(blocked.trim().parse::<IpAddr>().ok()?),
+            };
+            network
+                .contains(&ip)
+                .then_some((network.prefix_len(), blocked, rule_name))
+        })
+        // Equally long prefixes are told apart by the entry itself, for a stable choice
+        .max_by_key(|(prefix_len, blocked, _)| (*prefix_len, Reverse(*blocked)))
+        .map(|(_, blocked, rule_name)| {
+            trace!("IP {} matched blocked entry {}", ip, blocked);
+            configured_block_response(rule_name, env.ip_block_status, &env.ip_block_body)
+        })
+}
+
+pub fn evaluate_bot(bot_name: &str, env: &EnvConfig) -> BlockResponse {
+    trace!("Blocking bot traffic from {}", bot_name);
+    configured_block_response("Bot Traffic", env.bot_block_status, &env.bot_block_body)
+}
+
+// Bodies from the env config are sent as JSON when they parse as a JSON document, as text otherwise
+fn configured_block_response(blocked_by: &str, status: i32, body: &str) -> BlockResponse {
+    let mut headers = HashMap::new();
+    let body = match serde_json::from_str::<Value>(body) {
+        Ok(Value::String(_)) | Err(_) => Value::String(body.to_string()),
+        Ok(json) => {
+            headers.insert("content-type".to_string(), "application/json".to_string());
+            json
+        }
+    };
+    BlockResponse {
+        blocked_by: blocked_by.to_string(),
+        status,
+        headers,
+        body,
+    }
+}
+
+fn evaluate_regex_rules(rules: &[GovernanceRule], event: &Event) -> Option<BlockResponse> {
+    rules
+        .iter()
+        .filter(|rule| rule.block && rule.rule_type == "regex" && !rule.regex_config.is_empty())
+        .find(|rule| matches_regex_config(rule, event))
+        .map(|rule| {
+            trace!("Request matched governance rule {}", rule.id);
+            let values = get_variable_values(rule, event);
+            build_block_response(rule, Some(&values))
+        })
+}
+
+// Any of the rule's regex configs can match, entity rules without one apply to every request
+fn matches_regex_config(rule: &GovernanceRule, event: &Event) -> bool {
+    rule.regex_config.is_empty()
+        || rule
+            .regex_config
+            .iter()
+            .any(|regex_rule| matches_conditions(&regex_rule.conditions, event))
+}
+
+fn get_variable_values(rule: &GovernanceRule, event: &Event) -> HashMap<String, String> {
+    rule.variables
+        .iter()
+        .map(|variable| {
+            let value = get_field_value(event, &variable.path)
+                .unwrap_or_else(|| "UNKNOWN".to_string());
+            (variable.name.clone(), value)
+        })
+        .collect()
+}
+
+fn evaluate_entity_rules(
+    rules: &[GovernanceRule],
+    rule_type: &str,
+    event: &Event,
+    entity_id: &Option<String>,
+    entity_rules: &HashMap<String, Vec<EntityRuleValues>>,
+) -> Option<BlockResponse> {
+    rules
+        .iter()
+        .filter(|rule| rule.block && rule.rule_type == rule_type)
+        .filter(|rule| matches_regex_config(rule, event))
+        .find_map(|rule| {
+            let entity_id = match entity_id {
+                Some(entity_id) => entity_id,
+                None if rule.applied_to_unidentified => {
+                    return Some(build_block_response(rule, None));
+                }
+                None => return None,
+            };
+
+            // The app config lists the entities that are in the rule's cohort
+            let cohort_values = entity_rules
+                .get(entity_id)
+                .and_then(|values| values.iter().find(|v| v.rules == rule.id));
+            let applies = match rule.applied_to.as_deref() {
+                Some("not_matching") => cohort_values.is_none(),
+                _ => cohort_values.is_some(),
+            };
+            if !applies {
+                return None;
+            }
+
+            trace!("{} {} matched governance rule {}", rule_type, entity_id, rule.id);
+            Some(build_block_response(
+                rule,
+                cohort_values.and_then(|v| v.values.as_ref()),
+            ))
+        })
+}
+
+fn build_block_response(
+    rule: &GovernanceRule,
+    values: Option<&HashMap<String, String>>,
+) -> BlockResponse {
+    let empty = HashMap::new();
+    let values = values.unwrap_or(&empty);
+
+    let headers = rule
+        .response
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), replace_template_values(value, values)))
+        .collect();
+
+    let body = match &rule.response.body {
+        Some(body) => replace_body_values(body, values),
+        None => Value::Null,
+    };
+
+    let status = match rule.response.status {
+        status @ 100..=599 => status,
+        status => {
+            log::warn!(
+                "Governance rule {} has invalid response status {}, using {}",
+                rule.id,
+                status,
+                DEFAULT_RULE_BLOCK_STATUS
+            );
+            DEFAULT_RULE_BLOCK_STATUS
+        }
+    };
+
+    BlockResponse {
+        blocked_by: rule.id.clone(),
+        status,
+        headers,
+        body,
+    }
+}
+
+// Substitutes into the string values of the body, so values with quotes keep the JSON intact
+fn replace_body_values(body: &Value, values: &HashMap<String, String>) -> Value {
+    match body {
+        Value::String(template) => Value::String(replace_template_values(template, values)),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| replace_body_values(item, values))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), replace_body_values(value, values)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Rule templates reference cohort values as {{name}}
+fn replace_template_values(template: &str, values: &HashMap<String, String>) -> String {
+    values.iter().fold(template.to_string(), |acc, (name, value)| {
+        acc.replace(&format!("{{{{{}}}}}", name), value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{app_config, event, test_env};
+    use serde_json::json;
+
+    fn rule(rule: Value) -> GovernanceRule {
+        let mut rule: GovernanceRule = serde_json::from_value(rule).unwrap();
+        rule.compile_regexes();
+        rule
+    }
+
+    fn user_rule(applied_to: &str, applied_to_unidentified: bool) -> GovernanceRule {
+        rule(json!({
+            "_id": "user-rule",
+            "type": "user",
+            "block": true,
+            "applied_to": applied_to,
+            "applied_to_unidentified": applied_to_unidentified,
+            "response": {
+                "status": 429,
+                "headers": {"x-plan": "{{plan}}"},
+                "body": {"error": "Plan {{plan}} is over its quota"},
+            },
+        }))
+    }
+
+    #[test]
+    fn blocks_users_in_the_cohort_with_substituted_values() {
+        let app_config = app_config(json!({"user_rules": {
+            "user-1": [{"rules": "user-rule", "values": {"plan": "free"}}],
+        }}));
+        let rules = [user_rule("matching", false)];
+
+        let user_1 = event("/items", Some("user-1"), None);
+        let response = evaluate(&app_config, &rules, &user_1).unwrap();
+        assert_eq!(response.blocked_by, "user-rule");
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers["x-plan"], "free");
+        assert_eq!(response.body, json!({"error": "Plan free is over its quota"}));
+
+        let user_2 = event("/items", Some("user-2"), None);
+        assert!(evaluate(&app_config, &rules, &user_2).is_none());
+    }
+
+    #[test]
+    fn not_matching_rules_block_users_outside_the_cohort() {
+        let app_config = app_config(json!({"user_rules": {
+            "paying-user": [{"rules": "user-rule", "values": {"plan": "pro"}}],
+        }}));
+        let rules = [user_rule("not_matching", false)];
+
+        let paying_user = event("/items", Some("paying-user"), None);
+        assert!(evaluate(&app_config, &rules, &paying_user).is_none());
+        let free_user = event("/items", Some("free-user"), None);
+        let response = evaluate(&app_config, &rules, &free_user).unwrap();
+        assert_eq!(response.blocked_by, "user-rule");
+        // Users outside the cohort have no values, so the template is left as is
+        assert_eq!(response.headers["x-plan"], "{{plan}}");
+    }
+
+    #[test]
+    fn unidentified_requests_are_only_blocked_when_the_rule_applies_to_them() {
+        let app_config = app_config(json!({}));
+        let unidentified = event("/items", None, None);
+
+        assert!(evaluate(&app_config, &[user_rule("matching", false)], &unidentified).is_none());
+        let response =
+            evaluate(&app_config, &[user_rule("matching", true)], &unidentified).unwrap();
+        assert_eq!(response.blocked_by, "user-rule");
+    }
+
+    #[test]
+    fn rules_that_do_not_block_are_ignored() {
+        let app_config = app_config(json!({"user_rules": {"user-1": [{"rules": "user-rule"}]}}));
+        let mut rule = user_rule("matching", true);
+        rule.block = false;
+
+        let user_1 = event("/items", Some("user-1"), None);
+        assert!(evaluate(&app_config, &[rule.clone()], &user_1).is_none());
+        assert!(evaluate(&app_config, &[rule], &event("/items", None, None)).is_none());
+    }
+
+    #[test]
+    fn regex_rules_substitute_variables_from_the_request() {
+        let rules = [rule(json!({
+            "_id": "regex-rule",
+            "type": "regex",
+            "block": true,
+            "regex_config": [{"conditions": [{"path": "request.route", "value": "^/admin"}]}],
+            "variables": [
+                {"name": "route", "path": "request.route"},
+                {"name": "tenant", "path": "request.headers.X-Tenant"},
+            ],
+            "response": {"status": 403, "body": {"error": "{{route}} is blocked for {{tenant}}"}},
+        }))];
+        let app_config = AppConfigResponse::default();
+
+        let mut admin = event("/admin/users?page=2", None, None);
+        admin.request.headers.insert("x-tenant".to_string(), "acme".to_string());
+        let response = evaluate(&app_config, &rules, &admin).unwrap();
+        assert_eq!(response.blocked_by, "regex-rule");
+        assert_eq!(response.body, json!({"error": "/admin/users is blocked for acme"}));
+
+        assert!(evaluate(&app_config, &rules, &event("/items", None, None)).is_none());
+    }
+
+    #[test]
+    fn substituted_values_keep_the_body_valid_json() {
+        let app_config = app_config(json!({"user_rules": {
+            "user-1": [{"rules": "user-rule", "values": {"plan": "\"pro\" \\ annual"}}],
+        }}));
+        let rules = [user_rule("matching", false)];
+
+        let user_1 = event("/items", Some("user-1"), None);
+        let response = evaluate(&app_config, &rules, &user_1).unwrap();
+        assert_eq!(
+            response.body,
+            json!({"error": "Plan \"pro\" \\ annual is over its quota"})
+        );
+    }
+
+    #[test]
+    fn rules_without_a_valid_status_block_with_403() {
+        let app_config = app_config(json!({}));
+        for response in [json!({}), json!({"status": 0}), json!({"status": 1000})] {
+            let rule = rule(json!({
+                "_id": "user-rule",
+                "type": "user",
+                "block": true,
+                "applied_to_unidentified": true,
+                "response": response,
+            }));
+            let response = evaluate(&app_config, &[rule], &event("/items", None, None)).unwrap();
+            assert_eq!(response.status, 403);
+        }
+    }
+
+    #[test]
+    fn user_rules_take_precedence_over_regex_rules() {
+        let app_config = app_config(json!({"user_rules": {"user-1": [{"rules": "user-rule"}]}}));
+        let rules = [
+            rule(json!({
+                "_id": "regex-rule",
+                "type": "regex",
+                "block": true,
+                "regex_config": [{"conditions": [{"path": "request.verb", "value": "GET"}]}],
+                "response": {"status": 403},
+            })),
+            user_rule("matching", false),
+        ];
+
+        let user_1 = event("/items", Some("user-1"), None);
+        let response = evaluate(&app_config, &rules, &user_1).unwrap();
+        assert_eq!(response.blocked_by, "user-rule");
+        let user_2 = event("/items", Some("user-2"), None);
+        let response = evaluate(&app_config, &rules, &user_2).unwrap();
+        assert_eq!(response.blocked_by, "regex-rule");
+    }
+
+    fn blocked_ips() -> HashMap<String, String> {
+        HashMap::from([
+            ("203.0.113.7".to_string(), "Single IP".to_string()),
+            ("198.51.100.0/24".to_string(), "IPv4 range".to_string()),
+            ("2001:db8::/32".to_string(), "IPv6 range".to_string()),
+        ])
+    }
+
+    #[test]
+    fn blocks_a_single_ip() {
+        let env = test_env("http://localhost", json!({}));
+        let response = evaluate_blocked_ip(&blocked_ips(), "203.0.113.7", &env).unwrap();
+        assert_eq!(response.blocked_by, "Single IP");
+        assert_eq!(response.status, 403);
+        assert_eq!(response.body, json!({"error": "Your IP address is blocked."}));
+        assert_eq!(response.headers["content-type"], "application/json");
+
+        assert!(evaluate_blocked_ip(&blocked_ips(), "203.0.113.8", &env).is_none());
+    }
+
+    #[test]
+    fn blocks_ips_in_a_cidr_range() {
+        let env = test_env("http://localhost", json!({}));
+        for (ip, rule_name) in [
+            ("198.51.100.1", "IPv4 range"),
+            ("198.51.100.255", "IPv4 range"),
+            ("2001:db8:1::42", "IPv6 range"),
+        ] {
+            let response = evaluate_blocked_ip(&blocked_ips(), ip, &env).unwrap();
+            assert_eq!(response.blocked_by, rule_name, "{}", ip);
+        }
+        assert!(evaluate_blocked_ip(&blocked_ips(), "198.51.101.1", &env).is_none());
+        assert!(evaluate_blocked_ip(&blocked_ips(), "not an ip", &env).is_none());
+    }
+
+    #[test]
+    fn overlapping_entries_use_the_longest_prefix() {
+        let env = test_env("http://localhost", json!({}));
+        let blocked_ips = HashMap::from([
+            ("10.0.0.0/8".to_string(), "Private range".to_string()),
+            ("10.1.0.0/16".to_string(), "Office range".to_string()),
+            ("10.1.2.3".to_string(), "Single IP".to_string()),
+        ]);
+        for (ip, rule_name) in [
+            ("10.1.2.3", "Single IP"),
+            ("10.1.2.4", "Office range"),
+            ("10.2.0.1", "Private range"),
+        ] {
+            let response = evaluate_blocked_ip(&blocked_ips, ip, &env).unwrap();
+            assert_eq!(response.blocked_by, rule_name, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn bot_block_responses_are_json() {
+        let env = test_env("http://localhost", json!({}));
+        let response = evaluate_bot("googlebot", &env);
+        assert_eq!(response.blocked_by, "Bot Traffic");
+        assert_eq!(response.status, 403);
+        assert_eq!(response.body, json!({"error": "Bot traffic is not allowed."}));
+        assert_eq!(response.headers["content-type"], "application/json");
+    }
+
+    #[test]
+    fn plain_text_block_bodies_have_no_json_content_type() {
+        let env = test_env(
+            "http://localhost",
+            json!({"ip_block_status": 451, "ip_block_body": "Blocked"}),
+        );
+        let response = evaluate_blocked_ip(&blocked_ips(), "203.0.113.7", &env).unwrap();
+        assert_eq!(response.status, 451);
+        assert_eq!(response.body, Value::String("Blocked".to_string()));
+        assert!(response.headers.is_empty());
+    }
+}
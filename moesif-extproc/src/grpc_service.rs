@@ -1,17 +1,26 @@
-use log::{error, info, trace};
+use log::{error, trace};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{ Response, Status};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use futures_util::StreamExt;
 use std::sync::Arc;
 
+use crate::bot;
 use crate::config::Config;
 use crate::event::{header_list_to_map, Event, ResponseInfo};
+use crate::governance::BlockResponse;
+use crate::logging;
 use crate::root_context::EventRootContext;
-use crate::utils::*;
+use crate::sampling;
+use crate::telemetry;
 
+use envoy_ext_proc_proto::envoy::config::core::v3::{HeaderValue, HeaderValueOption};
+use envoy_ext_proc_proto::envoy::r#type::v3::HttpStatus;
 use envoy_ext_proc_proto::envoy::service::ext_proc::v3;
 
+#[derive(Clone)]
 pub struct MoesifGlooExtProcGrpcService {
     config: Arc<Config>, // Store the config in the service
     event_context: Arc<EventRootContext>,
@@ -31,6 +40,10 @@ impl MoesifGlooExtProcGrpcService {
 
         Ok(service)
     }
+
+    pub fn event_context(&self) -> Arc<EventRootContext> {
+        self.event_context.clone()
+    }
 }
 
 #[tonic::async_trait]
@@ -47,8 +60,12 @@ impl v3::external_processor_server::ExternalProcessor for MoesifGlooExtProcGrpcS
 
         let event_context = self.event_context.clone();
         let config = self.config.clone();
+        let active_stream = event_context.track_stream();
+        let stream_span = tracing::info_span!("ext_proc.stream");
+        let task_span = stream_span.clone();
 
-        tokio::spawn(async move {
+        tokio::spawn(logging::with_stream_context(async move {
+            let _active_stream = active_stream;
             let mut event = Event::new();
             let mut request_body_bytes = Vec::new();
             let mut response_body_bytes = Vec::new();
@@ -56,13 +73,25 @@ impl v3::external_processor_server::ExternalProcessor for MoesifGlooExtProcGrpcS
             while let Some(request) = stream.next().await {
                 match request {
                     Ok(req) => {
-                        // Process the ProcessingRequest and update the event
-                        let response: v3::ProcessingResponse = process_request(
-                            req,
-                            &mut event,
-                            &mut request_body_bytes,
-                            &mut response_body_bytes,
+                        // Continue the gateway's trace, before any phase span is created under the stream span
+                        if let Some(v3::processing_request::Request::RequestHeaders(headers_msg)) = &req.request {
+                            let headers = header_list_to_map(headers_msg.headers.clone());
+                            stream_span.set_parent(telemetry::extract_context(&headers));
+                        }
+                        let phase_span = tracing::info_span!(
+                            "ext_proc.phase",
+                            phase = req.request.as_ref().map(phase_name).unwrap_or("unknown")
                         );
+                        // Process the ProcessingRequest and update the event
+                        let response: v3::ProcessingResponse = phase_span.in_scope(|| {
+                            process_request(
+                                req,
+                                &mut event,
+                                &event_context,
+                                &mut request_body_bytes,
+                                &mut response_body_bytes,
+                            )
+                        });
                         // Send the ProcessingResponse back to the gateway
                         if let Err(e) = tx.send(Ok(response)).await {
                             trace!("Client closed connection: {:?}", e);
@@ -79,30 +108,88 @@ impl v3::external_processor_server::ExternalProcessor for MoesifGlooExtProcGrpcS
 
             // After the stream ends, set user and company IDs and send the event
             event.set_user_and_company_ids(&config);
-            event_context.push_event(event).await;
-        });
+            if config.env.skip_bot_traffic
+                && event.blocked_by.is_none()
+                && bot::get_bot_name(&event).is_some()
+            {
+                trace!("Skipping bot traffic event");
+                return;
+            }
+            let sampled = match event_context.app_config.read() {
+                Ok(app_config) => sampling::sample_event(&app_config, &mut event),
+                Err(_) => true,
+            };
+            if sampled {
+                event_context.push_event(event).await;
+            }
+        }.instrument(task_span)));
 
         // Return the receiver stream to send replies to the gateway
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
 
+fn phase_name(request: &v3::processing_request::Request) -> &'static str {
+    match request {
+        v3::processing_request::Request::RequestHeaders(_) => "request_headers",
+        v3::processing_request::Request::RequestBody(_) => "request_body",
+        v3::processing_request::Request::RequestTrailers(_) => "request_trailers",
+        v3::processing_request::Request::ResponseHeaders(_) => "response_headers",
+        v3::processing_request::Request::ResponseBody(_) => "response_body",
+        v3::processing_request::Request::ResponseTrailers(_) => "response_trailers",
+    }
+}
+
 // process the incoming processing request
 fn process_request(
     request: v3::ProcessingRequest,
     event: &mut Event,
+    event_context: &EventRootContext,
     request_body_bytes: &mut Vec<u8>,
     response_body_bytes: &mut Vec<u8>,
 ) -> v3::ProcessingResponse {
     let mut response = v3::ProcessingResponse::default();
 
     if let Some(req) = request.request {
+        event_context
+            .metrics
+            .phases
+            .with_label_values(&[phase_name(&req)])
+            .inc();
         match req {
             v3::processing_request::Request::RequestHeaders(headers_msg) => {
                 process_request_headers(&headers_msg, event);
-                response.response = Some(v3::processing_response::Response::RequestHeaders(
-                    v3::HeadersResponse::default(),
-                ));
+                let env = &event_context.config.env;
+                let transaction_id_generated =
+                    env.enable_transaction_id && event.set_transaction_id(&env.transaction_id_header);
+                if let Some(transaction_id) = &event.transaction_id {
+                    logging::set_transaction_id(transaction_id);
+                }
+                event.set_user_and_company_ids(&event_context.config);
+                if let Some(bot_name) = event_context.bot_classifier.classify(event) {
+                    bot::tag_event(event, &bot_name);
+                }
+                if let Some(mut block_response) = event_context.evaluate_governance(event) {
+                    trace!("Request blocked by {}", block_response.blocked_by);
+                    if let Some(transaction_id) = &event.transaction_id {
+                        block_response
+                            .headers
+                            .insert(env.transaction_id_header.clone(), transaction_id.clone());
+                    }
+                    response.response = Some(v3::processing_response::Response::ImmediateResponse(
+                        block_request(event, block_response),
+                    ));
+                } else {
+                    let headers_response = match &event.transaction_id {
+                        Some(transaction_id) if transaction_id_generated => {
+                            set_header_response(&env.transaction_id_header, transaction_id)
+                        }
+                        _ => v3::HeadersResponse::default(),
+                    };
+                    response.response = Some(v3::processing_response::Response::RequestHeaders(
+                        headers_response,
+                    ));
+                }
                 trace!("Processed Request Headers");
             }
             v3::processing_request::Request::RequestBody(body_msg) => {
@@ -120,8 +207,18 @@ fn process_request(
             }
             v3::processing_request::Request::ResponseHeaders(headers_msg) => {
                 process_response_headers(&headers_msg, event);
+                let headers_response = match (&event.transaction_id, &mut event.response) {
+                    (Some(transaction_id), Some(response_info)) => {
+                        let header_name = &event_context.config.env.transaction_id_header;
+                        response_info
+                            .headers
+                            .insert(header_name.clone(), transaction_id.clone());
+                        set_header_response(header_name, transaction_id)
+                    }
+                    _ => v3::HeadersResponse::default(),
+                };
                 response.response = Some(v3::processing_response::Response::ResponseHeaders(
-                    v3::HeadersResponse::default(),
+                    headers_response,
                 ));
                 trace!("Processed Response Headers");
             }
@@ -149,6 +246,63 @@ fn process_request_headers(headers_msg: &v3::HttpHeaders, event: &mut Event) {
     event.request.set_headers(headers_map);
 }
 
+// Record the blocked response on the event and build the reply sent to the client
+fn block_request(event: &mut Event, block_response: BlockResponse) -> v3::ImmediateResponse {
+    let body = match &block_response.body {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(body) => body.clone(),
+        body => body.to_string(),
+    };
+
+    let mut response_info = ResponseInfo::new();
+    response_info.status = block_response.status as usize;
+    response_info.headers = block_response.headers.clone();
+    response_info.body = block_response.body;
+    response_info.body_size = body.len();
+    event.response = Some(response_info);
+    event.blocked_by = Some(block_response.blocked_by);
+
+    v3::ImmediateResponse {
+        status: Some(HttpStatus {
+            code: block_response.status,
+        }),
+        headers: Some(header_mutation(
+            block_response
+                .headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )),
+        body: body.into(),
+        ..Default::default()
+    }
+}
+
+fn set_header_response(name: &str, value: &str) -> v3::HeadersResponse {
+    v3::HeadersResponse {
+        response: Some(v3::CommonResponse {
+            header_mutation: Some(header_mutation([(name, value)])),
+            ..Default::default()
+        }),
+    }
+}
+
+fn header_mutation<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> v3::HeaderMutation {
+    v3::HeaderMutation {
+        set_headers: headers
+            .into_iter()
+            .map(|(key, value)| HeaderValueOption {
+                header: Some(HeaderValue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
 fn process_request_body(
     body_msg: &v3::HttpBody,
     event: &mut Event,
@@ -156,7 +310,7 @@ fn process_request_body(
 ) {
     request_body_bytes.extend_from_slice(&body_msg.body);
     if body_msg.end_of_stream {
-        event.request.set_body(&request_body_bytes);
+        event.request.set_body(request_body_bytes);
     }
 }
 
@@ -181,7 +335,207 @@ fn process_response_body(
             event.response = Some(ResponseInfo::new());
         }
         if let Some(ref mut response_info) = event.response {
-            response_info.set_body(&response_body_bytes);
+            response_info.set_body(response_body_bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        request_headers, response_headers, run_stream, test_env, wait_for, MockResponse,
+        MockServer,
+    };
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    const TRANSACTION_ID_HEADER: &str = "x-moesif-transaction-id";
+
+    fn transaction_id_service(server: &MockServer) -> MoesifGlooExtProcGrpcService {
+        let env = test_env(
+            &server.base_uri,
+            json!({"enable_transaction_id": true, "batch_max_wait": 20}),
+        );
+        MoesifGlooExtProcGrpcService::new(Config { env }).unwrap()
+    }
+
+    // The headers the response sets, whichever phase it answers
+    fn mutation_headers(response: &v3::ProcessingResponse) -> HashMap<String, String> {
+        let mutation = match &response.response {
+            Some(v3::processing_response::Response::RequestHeaders(headers))
+            | Some(v3::processing_response::Response::ResponseHeaders(headers)) => headers
+                .response
+                .as_ref()
+                .and_then(|response| response.header_mutation.clone()),
+            Some(v3::processing_response::Response::ImmediateResponse(immediate)) => {
+                immediate.headers.clone()
+            }
+            _ => None,
+        };
+        mutation
+            .map(|mutation| mutation.set_headers)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|option| option.header)
+            .map(|header| (header.key, header.value))
+            .collect()
+    }
+
+    async fn sent_event(server: &MockServer) -> serde_json::Value {
+        wait_for("the event", || server.request_count("/v1/events/batch") == 1).await;
+        let batch = server.take_requests("/v1/events/batch").remove(0);
+        let mut events: Vec<serde_json::Value> = serde_json::from_slice(&batch.body).unwrap();
+        events.remove(0)
+    }
+
+    #[tokio::test]
+    async fn generated_transaction_ids_are_added_upstream_and_echoed_downstream() {
+        let server = MockServer::start();
+        let responses = run_stream(
+            transaction_id_service(&server),
+            vec![
+                request_headers(&[(":method", "GET"), (":path", "/items")]),
+                response_headers(&[(":status", "200")]),
+            ],
+        )
+        .await;
+
+        let upstream = mutation_headers(&responses[0]);
+        let transaction_id = upstream[TRANSACTION_ID_HEADER].clone();
+        assert!(uuid::Uuid::parse_str(&transaction_id).is_ok());
+        assert_eq!(mutation_headers(&responses[1])[TRANSACTION_ID_HEADER], transaction_id);
+
+        let event = sent_event(&server).await;
+        assert_eq!(event["transaction_id"], json!(transaction_id));
+        assert_eq!(event["request"]["headers"][TRANSACTION_ID_HEADER], json!(transaction_id));
+        assert_eq!(event["response"]["headers"][TRANSACTION_ID_HEADER], json!(transaction_id));
+    }
+
+    #[tokio::test]
+    async fn incoming_transaction_ids_are_reused_without_a_request_mutation() {
+        let server = MockServer::start();
+        let responses = run_stream(
+            transaction_id_service(&server),
+            vec![
+                request_headers(&[
+                    (":method", "GET"),
+                    (":path", "/items"),
+                    (TRANSACTION_ID_HEADER, "client-id"),
+                ]),
+                response_headers(&[(":status", "200")]),
+            ],
+        )
+        .await;
+
+        assert!(matches!(
+            &responses[0].response,
+            Some(v3::processing_response::Response::RequestHeaders(headers))
+                if headers.response.is_none()
+        ));
+        assert_eq!(mutation_headers(&responses[1])[TRANSACTION_ID_HEADER], "client-id");
+        assert_eq!(sent_event(&server).await["transaction_id"], json!("client-id"));
+    }
+
+    #[tokio::test]
+    async fn blocked_requests_carry_the_transaction_id() {
+        let server = MockServer::start();
+        server.respond("/v1/config", MockResponse::ok(r#"{"block_bot_traffic":true}"#));
+        let service = transaction_id_service(&server);
+        let event_context = service.event_context();
+        wait_for("the app config", || {
+            event_context.app_config.read().unwrap().block_bot_traffic
+        })
+        .await;
+
+        let responses = run_stream(
+            service,
+            vec![request_headers(&[
+                (":method", "GET"),
+                (":path", "/items"),
+                ("user-agent", "Googlebot/2.1"),
+            ])],
+        )
+        .await;
+
+        assert!(matches!(
+            &responses[0].response,
+            Some(v3::processing_response::Response::ImmediateResponse(_))
+        ));
+        let transaction_id = mutation_headers(&responses[0])[TRANSACTION_ID_HEADER].clone();
+        let event = sent_event(&server).await;
+        assert_eq!(event["blocked_by"], json!("Bot Traffic"));
+        assert_eq!(event["transaction_id"], json!(transaction_id));
+        assert_eq!(event["response"]["headers"][TRANSACTION_ID_HEADER], json!(transaction_id));
+    }
+
+    #[tokio::test]
+    async fn skip_bot_traffic_drops_bot_events() {
+        let server = MockServer::start();
+        let env = test_env(
+            &server.base_uri,
+            json!({"skip_bot_traffic": true, "batch_max_wait": 20}),
+        );
+        let service = MoesifGlooExtProcGrpcService::new(Config { env }).unwrap();
+
+        for (path, user_agent) in [("/bot", "Googlebot/2.1"), ("/browser", "Mozilla/5.0")] {
+            run_stream(
+                service.clone(),
+                vec![
+                    request_headers(&[
+                        (":method", "GET"),
+                        (":path", path),
+                        ("user-agent", user_agent),
+                    ]),
+                    response_headers(&[(":status", "200")]),
+                ],
+            )
+            .await;
         }
+
+        let event = sent_event(&server).await;
+        assert_eq!(event["request"]["uri"], json!("/browser"));
+    }
+
+    #[test]
+    fn block_request_builds_the_immediate_response_and_records_it_on_the_event() {
+        let mut event = Event::new();
+        let block_response = BlockResponse {
+            blocked_by: "user-rule".to_string(),
+            status: 429,
+            headers: HashMap::from([("x-plan".to_string(), "free".to_string())]),
+            body: json!({"error": "Plan free is over its quota"}),
+        };
+
+        let immediate_response = block_request(&mut event, block_response);
+        assert_eq!(immediate_response.status.unwrap().code, 429);
+        let headers = immediate_response.headers.unwrap().set_headers;
+        assert_eq!(headers.len(), 1);
+        let header = headers[0].header.as_ref().unwrap();
+        assert_eq!((header.key.as_str(), header.value.as_str()), ("x-plan", "free"));
+        let body: serde_json::Value = serde_json::from_slice(&immediate_response.body).unwrap();
+        assert_eq!(body, json!({"error": "Plan free is over its quota"}));
+
+        assert_eq!(event.blocked_by.as_deref(), Some("user-rule"));
+        let response = event.response.unwrap();
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers["x-plan"], "free");
+        assert_eq!(response.body, json!({"error": "Plan free is over its quota"}));
+    }
+
+    #[test]
+    fn block_request_sends_text_bodies_as_is() {
+        let mut event = Event::new();
+        let block_response = BlockResponse {
+            blocked_by: "Bot Traffic".to_string(),
+            status: 403,
+            headers: HashMap::new(),
+            body: json!("Forbidden"),
+        };
+
+        let immediate_response = block_request(&mut event, block_response);
+        assert_eq!(immediate_response.status.unwrap().code, 403);
+        assert_eq!(&immediate_response.body[..], b"Forbidden");
+        assert_eq!(event.blocked_by.as_deref(), Some("Bot Traffic"));
     }
 }
@@ -0,0 +1,121 @@
+use std::future::Future;
+
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer as ProcessorServer;
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::config::EnvConfig;
+use crate::grpc_service::MoesifGlooExtProcGrpcService;
+
+// Reports SERVING once the config is valid and the event processor is running. Shutdown aborts
+// the returned task, so it cannot report SERVING after set_not_serving.
+pub fn spawn_readiness_task(
+    mut health_reporter: HealthReporter,
+    env: EnvConfig,
+    processor_ready: impl Future<Output = bool> + Send + 'static,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        set_status(&mut health_reporter, ServingStatus::NotServing).await;
+        if let Err(e) = env.validate() {
+            error!("Health status stays NOT_SERVING due to invalid configuration: {}", e);
+            return;
+        }
+        if !processor_ready.await {
+            error!("Health status stays NOT_SERVING, the event processor did not start.");
+            return;
+        }
+        set_status(&mut health_reporter, ServingStatus::Serving).await;
+        info!("Health status set to SERVING.");
+    })
+}
+
+pub async fn set_not_serving(health_reporter: &mut HealthReporter) {
+    set_status(health_reporter, ServingStatus::NotServing).await;
+    info!("Health status set to NOT_SERVING.");
+}
+
+// Both the overall server status and the ExternalProcessor service status are reported
+async fn set_status(health_reporter: &mut HealthReporter, status: ServingStatus) {
+    health_reporter.set_service_status("", status).await;
+    match status {
+        ServingStatus::Serving => {
+            health_reporter
+                .set_serving::<ProcessorServer<MoesifGlooExtProcGrpcService>>()
+                .await
+        }
+        _ => {
+            health_reporter
+                .set_not_serving::<ProcessorServer<MoesifGlooExtProcGrpcService>>()
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{health_status, test_env};
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    async fn serve_health(env: EnvConfig) -> (SocketAddr, oneshot::Sender<bool>, JoinHandle<()>) {
+        let (health_reporter, health_service) = tonic_health::server::health_reporter();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let (ready_sender, ready) = oneshot::channel();
+        let readiness = spawn_readiness_task(health_reporter, env, async {
+            ready.await.unwrap_or(false)
+        });
+        (addr, ready_sender, readiness)
+    }
+
+    async fn wait_for_status(addr: SocketAddr, status: ServingStatus) {
+        for _ in 0..200 {
+            if health_status(addr).await == Some(status as i32) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(25)).await;
+        }
+        panic!("timed out waiting for health status {:?}", status);
+    }
+
+    #[tokio::test]
+    async fn reports_serving_once_the_processor_is_ready() {
+        let env = test_env("http://127.0.0.1:9", json!({}));
+        let (addr, ready_sender, readiness) = serve_health(env).await;
+
+        wait_for_status(addr, ServingStatus::NotServing).await;
+        ready_sender.send(true).unwrap();
+        readiness.await.unwrap();
+        assert_eq!(health_status(addr).await, Some(ServingStatus::Serving as i32));
+    }
+
+    #[tokio::test]
+    async fn stays_not_serving_with_an_invalid_config() {
+        let env = test_env("http://127.0.0.1:9", json!({"batch_max_bytes": 10}));
+        let (addr, _ready_sender, readiness) = serve_health(env).await;
+
+        readiness.await.unwrap();
+        assert_eq!(health_status(addr).await, Some(ServingStatus::NotServing as i32));
+    }
+
+    #[tokio::test]
+    async fn stays_not_serving_when_the_processor_does_not_start() {
+        let env = test_env("http://127.0.0.1:9", json!({}));
+        let (addr, ready_sender, readiness) = serve_health(env).await;
+
+        drop(ready_sender);
+        readiness.await.unwrap();
+        assert_eq!(health_status(addr).await, Some(ServingStatus::NotServing as i32));
+    }
+}
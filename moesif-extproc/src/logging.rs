@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, SetLoggerError};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::EnvConfig;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    static STREAM_CONTEXT: StreamContext;
+}
+
+// Identifies the ext_proc stream a log line was written for
+struct StreamContext {
+    stream_id: u64,
+    transaction_id: RefCell<Option<String>>,
+}
+
+// Runs the future with a new stream id that JSON log lines pick up
+pub async fn with_stream_context<F: Future>(future: F) -> F::Output {
+    let context = StreamContext {
+        stream_id: NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed),
+        transaction_id: RefCell::new(None),
+    };
+    STREAM_CONTEXT.scope(context, future).await
+}
+
+pub fn set_transaction_id(transaction_id: &str) {
+    let _ = STREAM_CONTEXT.try_with(|context| {
+        *context.transaction_id.borrow_mut() = Some(transaction_id.to_string());
+    });
+}
+
+pub fn init_logger(env: &EnvConfig) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(logger_builder(env).build()))
+}
+
+// RUST_LOG directives like "info,h2=warn" filter their targets in the logger. Other targets pass
+// through so that log::set_max_level alone decides, which lets the admin server change the
+// level at runtime.
+fn logger_builder(env: &EnvConfig) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    if let Some(rust_log) = &env.rust_log {
+        builder.parse_filters(rust_log);
+    }
+    builder.filter_level(LevelFilter::Trace);
+    if env.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut line = json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            let _ = STREAM_CONTEXT.try_with(|context| {
+                line["stream_id"] = Value::from(context.stream_id);
+                if let Some(transaction_id) = context.transaction_id.borrow().as_ref() {
+                    line["transaction_id"] = Value::from(transaction_id.as_str());
+                }
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder
+}
+
+// The level RUST_LOG sets for targets without a directive of their own, used as the max level
+pub fn default_level(rust_log: &str) -> Option<LevelFilter> {
+    let directives = rust_log.split('/').next().unwrap_or_default();
+    // As in env_logger, the last one wins
+    directives
+        .split(',')
+        .rev()
+        .find_map(|directive| LevelFilter::from_str(directive.trim()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_env;
+    use env_logger::Target;
+    use log::{Level, Log, Metadata, Record};
+    use std::sync::{Arc, Mutex};
+
+    // Collects what the logger writes, so tests can read it back
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn enabled(logger: &env_logger::Logger, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn filters_targets_named_in_rust_log() {
+        let env = test_env("http://localhost", json!({"rust_log": "info,h2=warn,hyper=off"}));
+        let logger = logger_builder(&env).build();
+
+        assert!(!enabled(&logger, "h2::codec", Level::Info));
+        assert!(enabled(&logger, "h2::codec", Level::Warn));
+        assert!(!enabled(&logger, "hyper", Level::Error));
+        // Left to the max level, so the admin server can raise it
+        assert!(enabled(&logger, "moesif_envoy_extproc_plugin", Level::Trace));
+    }
+
+    #[tokio::test]
+    async fn json_lines_carry_the_stream_context() {
+        let env = test_env("http://localhost", json!({"log_format": "json"}));
+        let captured = Captured::default();
+        let logger = logger_builder(&env)
+            .target(Target::Pipe(Box::new(captured.clone())))
+            .build();
+
+        with_stream_context(async {
+            set_transaction_id("transaction-1");
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("Processed \"request\" headers"))
+                    .level(Level::Info)
+                    .target("moesif_envoy_extproc_plugin::grpc_service")
+                    .build(),
+            );
+        })
+        .await;
+        logger.flush();
+
+        let output = captured.0.lock().unwrap().clone();
+        let line: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "moesif_envoy_extproc_plugin::grpc_service");
+        assert_eq!(line["message"], "Processed \"request\" headers");
+        assert!(line["stream_id"].as_u64().unwrap() > 0);
+        assert_eq!(line["transaction_id"], "transaction-1");
+    }
+
+    #[test]
+    fn reads_the_default_level_from_rust_log() {
+        assert_eq!(default_level("debug"), Some(LevelFilter::Debug));
+        assert_eq!(default_level("info,h2=warn"), Some(LevelFilter::Info));
+        assert_eq!(default_level("h2=warn, WARN"), Some(LevelFilter::Warn));
+        assert_eq!(default_level("error/timeout"), Some(LevelFilter::Error));
+        assert_eq!(default_level("h2=warn"), None);
+        assert_eq!(default_level("verbose"), None);
+    }
+}
\ No newline at end of file
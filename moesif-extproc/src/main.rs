@@ -1,17 +1,47 @@
+mod admin;
+mod bot;
 mod config;
 mod event;
+mod governance;
 mod grpc_service;
+mod health;
+mod logging;
+mod metrics;
+mod redaction;
+mod retry;
 mod root_context;
+mod sampling;
+mod spool;
+mod telemetry;
+#[cfg(test)]
+mod test_utils;
+mod tls;
 mod utils;
 
 use crate::config::{Config, EnvConfig};
 use crate::grpc_service::MoesifGlooExtProcGrpcService;
+use crate::root_context::EventRootContext;
+use crate::tls::TlsConfigReloader;
 use envoy_ext_proc_proto::envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer as ProcessorServer;
+use std::future::Future;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnixListenerStream;
 use tonic::transport::Server;
+use tonic_health::server::HealthReporter;
 use utils::set_and_display_log_level;
 
 async fn async_main(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "0.0.0.0:50051".parse()?;
+    let env = config.env.clone();
+
+    if let Err(e) = telemetry::init_tracing(&env) {
+        log::error!("Tracing is disabled: {}", e);
+    }
 
     // Initialize MoesifGlooExtProcGrpcService using the passed config
     let grpc_service = MoesifGlooExtProcGrpcService::new(config).map_err(|e| {
@@ -19,19 +49,168 @@ async fn async_main(config: Config) -> Result<(), Box<dyn std::error::Error>> {
         e
     })?;
 
-    log::info!(
-        "Starting Moesif ExtProc gRPC server for Solo.io Gloo Gateway on {}",
-        addr
+    let event_context = grpc_service.event_context();
+
+    if let Some(admin_addr) = env.admin_socket_addr()? {
+        admin::spawn_admin_server(admin_addr, event_context.clone());
+    }
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    let readiness_context = event_context.clone();
+    let readiness = health::spawn_readiness_task(health_reporter.clone(), env.clone(), async move {
+        readiness_context.wait_until_ready().await
+    });
+
+    let router = Server::builder()
+        .add_service(health_service)
+        .add_service(ProcessorServer::new(grpc_service));
+
+    let drain_period = Duration::from_millis(env.shutdown_drain_period);
+    let stream_timeout = Duration::from_millis(env.shutdown_stream_timeout);
+    let (listener_closing, listener_closed) = watch::channel(false);
+    let shutdown = drain_then_close(
+        shutdown_signal(),
+        health_reporter.clone(),
+        readiness,
+        drain_period,
+        listener_closing,
     );
 
-    Server::builder()
-        .add_service(ProcessorServer::new(grpc_service))
-        .serve(addr)
-        .await?;
+    if let Some(uds_path) = &env.grpc_uds_path {
+        let listener = bind_uds(uds_path)?;
+
+        log::info!(
+            "Starting Moesif ExtProc gRPC server for Solo.io Gloo Gateway on unix:{}",
+            uds_path
+        );
+
+        let server =
+            router.serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown);
+        serve_and_drain(server, listener_closed, &event_context, stream_timeout).await?;
+    } else if env.tls_enabled() {
+        let addr = env.grpc_socket_addr()?;
+        let tls = TlsConfigReloader::new(env.clone())?;
+        tls.spawn_reload_task();
+        let listener = TcpListener::bind(addr).await?;
+
+        log::info!(
+            "Starting Moesif ExtProc gRPC server for Solo.io Gloo Gateway on {} with TLS{}",
+            addr,
+            if env.tls_require_client_cert {
+                " and required client certificates"
+            } else {
+                ""
+            }
+        );
+
+        let server = router.serve_with_incoming_shutdown(tls.incoming(listener), shutdown);
+        serve_and_drain(server, listener_closed, &event_context, stream_timeout).await?;
+    } else {
+        let addr = env.grpc_socket_addr()?;
+
+        log::info!(
+            "Starting Moesif ExtProc gRPC server for Solo.io Gloo Gateway on {}",
+            addr
+        );
+
+        let server = router.serve_with_shutdown(addr, shutdown);
+        serve_and_drain(server, listener_closed, &event_context, stream_timeout).await?;
+    }
+
+    event_context
+        .shutdown(Duration::from_millis(env.shutdown_flush_timeout))
+        .await;
+    telemetry::shutdown_tracing().await;
 
     Ok(())
 }
 
+// Replaces the socket left behind by a previous run, but never removes anything that is not a socket
+fn bind_uds(path: &str) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("grpc_uds_path {} exists and is not a socket", path),
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+// Reports NOT_SERVING once the signal arrives but keeps serving for the drain period, so that
+// health checks see it and move traffic away, then resolves to close the listener
+async fn drain_then_close(
+    signal: impl Future<Output = ()>,
+    mut health_reporter: HealthReporter,
+    readiness: JoinHandle<()>,
+    drain_period: Duration,
+    listener_closing: watch::Sender<bool>,
+) {
+    signal.await;
+    // Wait for the abort to land, a readiness task mid-update could still report SERVING
+    readiness.abort();
+    let _ = readiness.await;
+    health::set_not_serving(&mut health_reporter).await;
+    log::info!("Serving for {:?} before closing the listener.", drain_period);
+    tokio::time::sleep(drain_period).await;
+    let _ = listener_closing.send(true);
+}
+
+// Serves until the listener closes, then gives open streams up to the stream timeout to finish
+async fn serve_and_drain(
+    server: impl Future<Output = Result<(), tonic::transport::Error>>,
+    mut listener_closed: watch::Receiver<bool>,
+    event_context: &EventRootContext,
+    stream_timeout: Duration,
+) -> Result<(), tonic::transport::Error> {
+    let drained = async {
+        server.await?;
+        while event_context.active_streams.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(())
+    };
+    let drain_deadline = async {
+        while !*listener_closed.borrow() {
+            if listener_closed.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+        log::info!("Draining open streams for up to {:?}.", stream_timeout);
+        tokio::time::sleep(stream_timeout).await;
+    };
+
+    tokio::select! {
+        result = drained => result,
+        _ = drain_deadline => {
+            log::warn!(
+                "Stream timeout elapsed with {} open streams, closing them.",
+                event_context.active_streams.load(Ordering::SeqCst)
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            log::error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("Received SIGTERM, shutting down."),
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT, shutting down."),
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize configuration
     let env_config = EnvConfig::new();
@@ -39,10 +218,177 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         env: env_config,
     };
 
+    logging::init_logger(&config.env)?;
     // Set the logging level based on the config
     set_and_display_log_level(&config);
-    env_logger::init();
 
     let runtime = tokio::runtime::Runtime::new()?;
     runtime.block_on(async_main(config))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::event::Event;
+    use crate::test_utils::{health_status, test_env, wait_for, MockServer};
+    use serde_json::json;
+    use tokio::net::UnixStream;
+    use tokio::sync::oneshot;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic_health::ServingStatus;
+
+    fn temp_socket_path() -> String {
+        std::env::temp_dir()
+            .join(format!("moesif-extproc-{}.sock", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn binds_and_replaces_a_stale_socket() {
+        let path = temp_socket_path();
+        let listener = bind_uds(&path).unwrap();
+        let client = UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+        drop(client);
+        drop(listener);
+
+        // The socket file outlives the listener, as after a crash
+        assert!(std::fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+        let listener = bind_uds(&path).unwrap();
+        UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_remove_a_regular_file() {
+        let path = temp_socket_path();
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let e = bind_uds(&path).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_not_serving_while_draining_then_closes_the_listener() {
+        let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+        health_reporter.set_service_status("", ServingStatus::Serving).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (signal_sender, signal) = oneshot::channel::<()>();
+        let (listener_closing, listener_closed) = watch::channel(false);
+        let shutdown = drain_then_close(
+            async {
+                let _ = signal.await;
+            },
+            health_reporter,
+            tokio::spawn(async {}),
+            Duration::from_secs(1),
+            listener_closing,
+        );
+        let server = tokio::spawn(
+            Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown),
+        );
+        assert_eq!(health_status(addr).await, Some(ServingStatus::Serving as i32));
+
+        signal_sender.send(()).unwrap();
+        let mut status = health_status(addr).await;
+        while status == Some(ServingStatus::Serving as i32) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            status = health_status(addr).await;
+        }
+        assert_eq!(status, Some(ServingStatus::NotServing as i32));
+        assert!(!*listener_closed.borrow());
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(*listener_closed.borrow());
+        assert_eq!(health_status(addr).await, None);
+    }
+
+    #[tokio::test]
+    async fn readiness_cannot_report_serving_once_shutdown_started() {
+        let (health_reporter, health_service) = tonic_health::server::health_reporter();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let (ready_sender, ready) = oneshot::channel();
+        let readiness = health::spawn_readiness_task(
+            health_reporter.clone(),
+            test_env("http://127.0.0.1:9", json!({})),
+            async { ready.await.unwrap_or(false) },
+        );
+        let (listener_closing, _) = watch::channel(false);
+
+        drain_then_close(async {}, health_reporter, readiness, Duration::ZERO, listener_closing)
+            .await;
+        // The processor becoming ready afterwards has nobody left to report it
+        assert!(ready_sender.send(true).is_err());
+        assert_eq!(health_status(addr).await, Some(ServingStatus::NotServing as i32));
+    }
+
+    #[tokio::test]
+    async fn drains_open_streams_before_flushing_their_events() {
+        let server = MockServer::start();
+        let event_context = EventRootContext::new(Config {
+            env: test_env(&server.base_uri, json!({"batch_max_wait": 60_000})),
+        });
+        let (_, listener_closed) = watch::channel(true);
+        let stream = event_context.track_stream();
+
+        let finish_stream = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            event_context.push_event(Event::new()).await;
+            drop(stream);
+        };
+        let drain = serve_and_drain(
+            async { Ok(()) },
+            listener_closed,
+            &event_context,
+            Duration::from_secs(5),
+        );
+        let (result, _) = tokio::join!(drain, finish_stream);
+        result.unwrap();
+        assert_eq!(event_context.active_streams.load(Ordering::SeqCst), 0);
+        assert_eq!(server.request_count("/v1/events/batch"), 0);
+
+        // Without a spool the queued event is sent rather than spooled
+        event_context.shutdown(Duration::from_secs(5)).await;
+        wait_for("the batch", || server.request_count("/v1/events/batch") == 1).await;
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_for_streams_after_the_stream_timeout() {
+        let server = MockServer::start();
+        let event_context = EventRootContext::new(Config {
+            env: test_env(&server.base_uri, json!({})),
+        });
+        let (_, listener_closed) = watch::channel(true);
+        let _stream = event_context.track_stream();
+
+        let drain = serve_and_drain(
+            async { Ok(()) },
+            listener_closed,
+            &event_context,
+            Duration::from_millis(100),
+        );
+        tokio::time::timeout(Duration::from_secs(5), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event_context.active_streams.load(Ordering::SeqCst), 1);
+    }
+}
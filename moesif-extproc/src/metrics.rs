@@ -0,0 +1,115 @@
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+// Prometheus metrics for the ext_proc streams, the event queue and the Moesif API calls
+pub struct Metrics {
+    registry: Registry,
+    pub streams_opened: IntCounter,
+    pub streams_closed: IntCounter,
+    pub active_streams: IntGauge,
+    pub phases: IntCounterVec,
+    pub events_enqueued: IntCounter,
+    pub events_dropped: IntCounterVec,
+    pub events_spooled: IntCounter,
+    pub queue_depth: IntGauge,
+    pub batch_events: Histogram,
+    pub batch_bytes: Histogram,
+    pub api_request_duration: HistogramVec,
+    pub api_responses: IntCounterVec,
+    pub upload_retries: IntCounter,
+    pub config_refreshes: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            streams_opened: IntCounter::new(
+                "moesif_streams_opened_total",
+                "ext_proc streams opened by Envoy",
+            )?,
+            streams_closed: IntCounter::new(
+                "moesif_streams_closed_total",
+                "ext_proc streams closed by Envoy",
+            )?,
+            active_streams: IntGauge::new("moesif_active_streams", "ext_proc streams currently open")?,
+            phases: IntCounterVec::new(
+                Opts::new(
+                    "moesif_processing_phases_total",
+                    "Processing requests received per phase",
+                ),
+                &["phase"],
+            )?,
+            events_enqueued: IntCounter::new(
+                "moesif_events_enqueued_total",
+                "Events added to the event queue",
+            )?,
+            events_dropped: IntCounterVec::new(
+                Opts::new("moesif_events_dropped_total", "Events dropped before reaching Moesif"),
+                &["reason"],
+            )?,
+            events_spooled: IntCounter::new(
+                "moesif_events_spooled_total",
+                "Events written to the disk spool",
+            )?,
+            queue_depth: IntGauge::new("moesif_queue_depth", "Events waiting in the event queue")?,
+            batch_events: Histogram::with_opts(
+                HistogramOpts::new("moesif_batch_events", "Events per batch sent to Moesif")
+                    .buckets(vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]),
+            )?,
+            batch_bytes: Histogram::with_opts(
+                HistogramOpts::new("moesif_batch_bytes", "Size in bytes of batches sent to Moesif")
+                    .buckets(exponential_buckets(1024.0, 4.0, 8)?),
+            )?,
+            api_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "moesif_api_request_duration_seconds",
+                    "Latency of requests to the Moesif API",
+                ),
+                &["path"],
+            )?,
+            api_responses: IntCounterVec::new(
+                Opts::new(
+                    "moesif_api_responses_total",
+                    "Responses from the Moesif API by status code, network errors have status \"error\"",
+                ),
+                &["path", "status"],
+            )?,
+            upload_retries: IntCounter::new(
+                "moesif_upload_retries_total",
+                "Batch uploads retried after a failure",
+            )?,
+            config_refreshes: IntCounterVec::new(
+                Opts::new(
+                    "moesif_config_refreshes_total",
+                    "App config and governance rules loaded from Moesif",
+                ),
+                &["kind"],
+            )?,
+        };
+
+        metrics.registry.register(Box::new(metrics.streams_opened.clone()))?;
+        metrics.registry.register(Box::new(metrics.streams_closed.clone()))?;
+        metrics.registry.register(Box::new(metrics.active_streams.clone()))?;
+        metrics.registry.register(Box::new(metrics.phases.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_enqueued.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_dropped.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_spooled.clone()))?;
+        metrics.registry.register(Box::new(metrics.queue_depth.clone()))?;
+        metrics.registry.register(Box::new(metrics.batch_events.clone()))?;
+        metrics.registry.register(Box::new(metrics.batch_bytes.clone()))?;
+        metrics.registry.register(Box::new(metrics.api_request_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.api_responses.clone()))?;
+        metrics.registry.register(Box::new(metrics.upload_retries.clone()))?;
+        metrics.registry.register(Box::new(metrics.config_refreshes.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
\ No newline at end of file
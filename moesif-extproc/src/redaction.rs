@@ -0,0 +1,539 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::{BodyMaskMode, EnvConfig, HeaderRedactionMode};
+use crate::event::Event;
+
+const MASK: &str = "*****";
+
+// Credential headers that are redacted unless header_deny_list is overridden
+pub const DEFAULT_HEADER_DENY_LIST: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+    "x-csrf-token",
+    "x-xsrf-token",
+    "x-amz-security-token",
+];
+
+// Patterns match the whole lowercase header name, e.g. "x-envoy-.*"
+pub fn compile_header_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| format!("Invalid header pattern {:?}: {}", pattern, e))
+}
+
+pub struct HeaderRedactor {
+    deny_list: HashSet<String>,
+    deny_patterns: Vec<Regex>,
+    allow_list: HashSet<String>,
+    mode: HeaderRedactionMode,
+}
+
+impl HeaderRedactor {
+    pub fn new(env: &EnvConfig) -> Self {
+        let deny_patterns = env
+            .header_deny_patterns
+            .iter()
+            .filter_map(|pattern| match compile_header_pattern(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    log::error!("{}, ignoring it.", e);
+                    None
+                }
+            })
+            .collect();
+        HeaderRedactor {
+            deny_list: env.header_deny_list.iter().cloned().collect(),
+            deny_patterns,
+            allow_list: env.header_allow_list.iter().cloned().collect(),
+            mode: env.header_redaction_mode,
+        }
+    }
+
+    // Headers outside a non-empty allow list are redacted as well
+    fn is_denied(&self, name: &str) -> bool {
+        self.deny_list.contains(name)
+            || self.deny_patterns.iter().any(|pattern| pattern.is_match(name))
+            || (!self.allow_list.is_empty() && !self.allow_list.contains(name))
+    }
+
+    pub fn redact_headers(&self, headers: &mut HashMap<String, String>) {
+        match self.mode {
+            HeaderRedactionMode::Remove => headers.retain(|name, _| !self.is_denied(name)),
+            HeaderRedactionMode::Mask => {
+                for (name, value) in headers.iter_mut() {
+                    if self.is_denied(name) {
+                        *value = MASK.to_string();
+                    }
+                }
+            }
+            HeaderRedactionMode::Sha256 => {
+                for (name, value) in headers.iter_mut() {
+                    if self.is_denied(name) {
+                        *value = format!("{:x}", Sha256::digest(value.as_bytes()));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn redact_event(&self, event: &mut Event) {
+        self.redact_headers(&mut event.request.headers);
+        if let Some(response) = event.response.as_mut() {
+            self.redact_headers(&mut response.headers);
+        }
+    }
+}
+
+// A key name matched at any depth, ignoring case, or a JSONPath like $.card.number
+enum BodyField {
+    Key(String),
+    Path(Vec<PathSegment>),
+}
+
+// The supported JSONPath subset: .key, ['key'], [0], .* or [*], and ..key
+enum PathSegment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+    Descendant(String),
+}
+
+fn parse_body_field(field: &str) -> Result<BodyField, String> {
+    match field.strip_prefix('$') {
+        Some(path) => parse_path(path)
+            .map(BodyField::Path)
+            .map_err(|e| format!("Invalid JSONPath {:?}: {}", field, e)),
+        None => Ok(BodyField::Key(field.to_lowercase())),
+    }
+}
+
+pub fn validate_body_field(field: &str) -> Result<(), String> {
+    parse_body_field(field).map(|_| ())
+}
+
+fn parse_path(path: &str) -> Result<Vec<PathSegment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            let (name, remaining) = split_name(after);
+            if name.is_empty() || name == "*" {
+                return Err("expected a key name after ..".to_string());
+            }
+            segments.push(PathSegment::Descendant(name.to_string()));
+            rest = remaining;
+        } else if let Some(after) = rest.strip_prefix('.') {
+            let (name, remaining) = split_name(after);
+            segments.push(match name {
+                "" => return Err("expected a key name after .".to_string()),
+                "*" => PathSegment::Wildcard,
+                name => PathSegment::Key(name.to_string()),
+            });
+            rest = remaining;
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or("missing ]")?;
+            let selector = after[..end].trim();
+            let quoted = selector
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+                .or_else(|| selector.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+            segments.push(match quoted {
+                Some(name) => PathSegment::Key(name.to_string()),
+                None if selector == "*" => PathSegment::Wildcard,
+                None => PathSegment::Index(
+                    selector
+                        .parse()
+                        .map_err(|_| format!("unsupported selector [{}]", selector))?,
+                ),
+            });
+            rest = &after[end + 1..];
+        } else {
+            return Err(format!("unexpected {:?}", rest));
+        }
+    }
+    if segments.is_empty() {
+        return Err("the path has to select a field".to_string());
+    }
+    Ok(segments)
+}
+
+fn split_name(path: &str) -> (&str, &str) {
+    let end = path.find(['.', '[']).unwrap_or(path.len());
+    path.split_at(end)
+}
+
+pub struct BodyMasker {
+    request_fields: Vec<BodyField>,
+    response_fields: Vec<BodyField>,
+    mode: BodyMaskMode,
+}
+
+impl BodyMasker {
+    pub fn new(env: &EnvConfig) -> Self {
+        BodyMasker {
+            request_fields: parse_body_fields(&env.request_body_mask_fields),
+            response_fields: parse_body_fields(&env.response_body_mask_fields),
+            mode: env.body_mask_mode,
+        }
+    }
+
+    // Only JSON bodies are masked, base64 encoded ones are left as they are
+    pub fn mask_event(&self, event: &mut Event) {
+        if event.request.transfer_encoding.is_none() {
+            self.mask_body(&mut event.request.body, &self.request_fields);
+        }
+        if let Some(response) = event.response.as_mut() {
+            if response.transfer_encoding.is_none() {
+                self.mask_body(&mut response.body, &self.response_fields);
+            }
+        }
+    }
+
+    fn mask_body(&self, body: &mut Value, fields: &[BodyField]) {
+        for field in fields {
+            match field {
+                BodyField::Key(key) => mask_key(body, key, self.mode),
+                BodyField::Path(path) => mask_path(body, path, self.mode),
+            }
+        }
+    }
+}
+
+fn parse_body_fields(fields: &[String]) -> Vec<BodyField> {
+    fields
+        .iter()
+        .filter_map(|field| match parse_body_field(field) {
+            Ok(field) => Some(field),
+            Err(e) => {
+                log::error!("{}, ignoring it.", e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn mask_key(value: &mut Value, key: &str, mode: BodyMaskMode) {
+    match value {
+        Value::Object(map) => {
+            match mode {
+                BodyMaskMode::Remove => map.retain(|name, _| !name.eq_ignore_ascii_case(key)),
+                BodyMaskMode::Mask => {
+                    for (name, value) in map.iter_mut() {
+                        if name.eq_ignore_ascii_case(key) {
+                            *value = Value::String(MASK.to_string());
+                        }
+                    }
+                }
+            }
+            for value in map.values_mut() {
+                mask_key(value, key, mode);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                mask_key(item, key, mode);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn mask_path(value: &mut Value, path: &[PathSegment], mode: BodyMaskMode) {
+    let Some((segment, rest)) = path.split_first() else {
+        return;
+    };
+    match segment {
+        PathSegment::Key(key) => mask_child(value, key, rest, mode),
+        PathSegment::Index(index) => {
+            if let Value::Array(items) = value {
+                if *index >= items.len() {
+                    return;
+                }
+                if !rest.is_empty() {
+                    mask_path(&mut items[*index], rest, mode);
+                } else if mode == BodyMaskMode::Remove {
+                    items.remove(*index);
+                } else {
+                    items[*index] = Value::String(MASK.to_string());
+                }
+            }
+        }
+        PathSegment::Wildcard => {
+            let children: Vec<&mut Value> = match value {
+                Value::Object(map) if rest.is_empty() && mode == BodyMaskMode::Remove => {
+                    map.clear();
+                    return;
+                }
+                Value::Array(items) if rest.is_empty() && mode == BodyMaskMode::Remove => {
+                    items.clear();
+                    return;
+                }
+                Value::Object(map) => map.values_mut().collect(),
+                Value::Array(items) => items.iter_mut().collect(),
+                _ => return,
+            };
+            for child in children {
+                if rest.is_empty() {
+                    *child = Value::String(MASK.to_string());
+                } else {
+                    mask_path(child, rest, mode);
+                }
+            }
+        }
+        // Applies the rest of the path below the key at this level and every level below it
+        PathSegment::Descendant(key) => {
+            mask_child(value, key, rest, mode);
+            match value {
+                Value::Object(map) => {
+                    for child in map.values_mut() {
+                        mask_path(child, path, mode);
+                    }
+                }
+                Value::Array(items) => {
+                    for item in items {
+                        mask_path(item, path, mode);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn mask_child(value: &mut Value, key: &str, rest: &[PathSegment], mode: BodyMaskMode) {
+    if let Value::Object(map) = value {
+        if !rest.is_empty() {
+            if let Some(child) = map.get_mut(key) {
+                mask_path(child, rest, mode);
+            }
+        } else if mode == BodyMaskMode::Remove {
+            map.remove(key);
+        } else if let Some(child) = map.get_mut(key) {
+            *child = Value::String(MASK.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ResponseInfo;
+    use crate::test_utils::test_env;
+    use serde_json::json;
+
+    fn redact(overrides: Value, headers: &[(&str, &str)]) -> HashMap<String, String> {
+        let redactor = HeaderRedactor::new(&test_env("http://localhost", overrides));
+        let mut headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        redactor.redact_headers(&mut headers);
+        headers
+    }
+
+    #[test]
+    fn masks_credential_headers_by_default() {
+        let headers = redact(
+            json!({}),
+            &[
+                ("authorization", "Bearer abc"),
+                ("cookie", "session=1"),
+                ("x-api-key", "key"),
+                ("content-type", "application/json"),
+            ],
+        );
+        assert_eq!(headers["authorization"], MASK);
+        assert_eq!(headers["cookie"], MASK);
+        assert_eq!(headers["x-api-key"], MASK);
+        assert_eq!(headers["content-type"], "application/json");
+    }
+
+    #[test]
+    fn deny_patterns_match_the_whole_header_name() {
+        let headers = redact(
+            json!({"header_deny_patterns": ["x-envoy-.*"]}),
+            &[
+                ("x-envoy-original-path", "/internal"),
+                ("x-not-x-envoy-header", "kept"),
+                ("x-envoy", "kept"),
+            ],
+        );
+        assert_eq!(headers["x-envoy-original-path"], MASK);
+        assert_eq!(headers["x-not-x-envoy-header"], "kept");
+        assert_eq!(headers["x-envoy"], "kept");
+    }
+
+    #[test]
+    fn allow_list_redacts_every_other_header() {
+        let headers = redact(
+            json!({"header_allow_list": ["content-type", "authorization"]}),
+            &[
+                ("content-type", "application/json"),
+                ("x-custom", "value"),
+                ("authorization", "Bearer abc"),
+            ],
+        );
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-custom"], MASK);
+        // The deny list still applies to allowed headers
+        assert_eq!(headers["authorization"], MASK);
+    }
+
+    #[test]
+    fn removes_or_hashes_headers_per_redaction_mode() {
+        let headers = [("authorization", "secret"), ("accept", "*/*")];
+
+        let removed = redact(json!({"header_redaction_mode": "remove"}), &headers);
+        assert!(!removed.contains_key("authorization"));
+        assert_eq!(removed["accept"], "*/*");
+
+        let hashed = redact(json!({"header_redaction_mode": "sha256"}), &headers);
+        assert_eq!(
+            hashed["authorization"],
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+        assert_eq!(hashed["accept"], "*/*");
+
+        let masked = redact(json!({"header_redaction_mode": "mask"}), &headers);
+        assert_eq!(masked["authorization"], MASK);
+    }
+
+    fn body_masker(overrides: Value) -> BodyMasker {
+        BodyMasker::new(&test_env("http://localhost", overrides))
+    }
+
+    // Masks the body as a request body with the given fields
+    fn mask(fields: &[&str], mode: &str, body: Value) -> Value {
+        let masker = body_masker(json!({
+            "request_body_mask_fields": fields,
+            "body_mask_mode": mode,
+        }));
+        let mut event = Event::new();
+        event.request.body = body;
+        masker.mask_event(&mut event);
+        event.request.body
+    }
+
+    #[test]
+    fn masks_a_nested_path() {
+        let body = json!({"card": {"number": "4111", "exp": "12/30"}, "number": "1"});
+        assert_eq!(
+            mask(&["$.card.number"], "mask", body),
+            json!({"card": {"number": "*****", "exp": "12/30"}, "number": "1"})
+        );
+    }
+
+    #[test]
+    fn masks_every_array_item_with_a_wildcard() {
+        let body = json!({"items": [{"cvv": "123", "sku": "a"}, {"cvv": "456", "sku": "b"}]});
+        assert_eq!(
+            mask(&["$.items[*].cvv"], "mask", body),
+            json!({"items": [{"cvv": "*****", "sku": "a"}, {"cvv": "*****", "sku": "b"}]})
+        );
+    }
+
+    #[test]
+    fn masks_descendants_at_any_depth() {
+        let body = json!({"token": "a", "auth": {"token": "b", "sessions": [{"token": "c"}]}});
+        assert_eq!(
+            mask(&["$..token"], "mask", body),
+            json!({"token": "*****", "auth": {"token": "*****", "sessions": [{"token": "*****"}]}})
+        );
+    }
+
+    #[test]
+    fn masks_and_removes_array_indexes() {
+        let body = json!({"items": ["a", "b", "c"]});
+        assert_eq!(
+            mask(&["$.items[0]"], "mask", body.clone()),
+            json!({"items": ["*****", "b", "c"]})
+        );
+        assert_eq!(mask(&["$.items[0]"], "remove", body.clone()), json!({"items": ["b", "c"]}));
+        // An index past the end is ignored
+        assert_eq!(mask(&["$.items[3]"], "mask", body.clone()), body);
+    }
+
+    #[test]
+    fn matches_key_names_at_any_depth_ignoring_case() {
+        let body = json!({
+            "Password": "a",
+            "user": {"PASSWORD": "b", "name": "c"},
+            "history": [{"password": "d"}],
+        });
+        assert_eq!(
+            mask(&["password"], "mask", body.clone()),
+            json!({
+                "Password": "*****",
+                "user": {"PASSWORD": "*****", "name": "c"},
+                "history": [{"password": "*****"}],
+            })
+        );
+        assert_eq!(
+            mask(&["password"], "remove", body),
+            json!({"user": {"name": "c"}, "history": [{}]})
+        );
+    }
+
+    #[test]
+    fn removes_fields_in_remove_mode() {
+        let body = json!({"card": {"number": "4111", "exp": "12/30"}, "items": [{"cvv": "1"}]});
+        assert_eq!(
+            mask(&["$.card.number", "$.items[*].cvv"], "remove", body),
+            json!({"card": {"exp": "12/30"}, "items": [{}]})
+        );
+    }
+
+    #[test]
+    fn validates_body_fields() {
+        let valid = [
+            "password",
+            "$.card.number",
+            "$['card'].number",
+            "$.items[*].cvv",
+            "$..token",
+            "$[0]",
+        ];
+        for field in valid {
+            assert!(validate_body_field(field).is_ok(), "{} should be valid", field);
+        }
+        for field in ["$", "$.", "$[x]", "$..*", "$.items[0", "$card"] {
+            assert!(validate_body_field(field).is_err(), "{} should be invalid", field);
+        }
+    }
+
+    #[test]
+    fn leaves_base64_bodies_untouched() {
+        let masker = body_masker(json!({"request_body_mask_fields": ["$..token"]}));
+        let mut event = Event::new();
+        event.request.body = json!({"token": "a"});
+        event.request.transfer_encoding = Some("base64".to_string());
+        masker.mask_event(&mut event);
+
+        assert_eq!(event.request.body, json!({"token": "a"}));
+    }
+
+    #[test]
+    fn applies_request_and_response_fields_separately() {
+        let masker = body_masker(json!({
+            "request_body_mask_fields": ["$.password"],
+            "response_body_mask_fields": ["$.token"],
+        }));
+        let mut event = Event::new();
+        event.request.body = json!({"password": "a", "token": "b"});
+        let mut response = ResponseInfo::new();
+        response.body = json!({"password": "c", "token": "d"});
+        event.response = Some(response);
+        masker.mask_event(&mut event);
+
+        assert_eq!(event.request.body, json!({"password": "*****", "token": "b"}));
+        let response = event.response.unwrap();
+        assert_eq!(response.body, json!({"password": "c", "token": "*****"}));
+    }
+}
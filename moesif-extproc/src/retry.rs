@@ -0,0 +1,183 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+
+use crate::config::EnvConfig;
+
+// Returned by dispatch_http_request when the Moesif API responds with a non-2xx status
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Moesif API responded with status {}", self.status)
+    }
+}
+
+impl Error for HttpStatusError {}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_retry_after: Duration,
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    pub fn new(env: &EnvConfig) -> Self {
+        Self {
+            max_attempts: env.retry_max_attempts.max(1),
+            base_backoff: Duration::from_millis(env.retry_base_backoff),
+            max_backoff: Duration::from_millis(env.retry_max_backoff),
+            max_retry_after: Duration::from_millis(env.retry_after_max),
+            jitter: env.retry_jitter,
+        }
+    }
+
+    // Network errors, 5xx and 429 are worth retrying, other 4xx like an invalid application id are not
+    pub fn is_retryable(&self, error: &(dyn Error + Send + Sync + 'static)) -> bool {
+        if let Some(e) = error.downcast_ref::<HttpStatusError>() {
+            return e.status == 429 || (500..=599).contains(&e.status);
+        }
+        error.downcast_ref::<reqwest::Error>().is_some()
+    }
+
+    // Only a payload Moesif refuses to accept is worth dropping, auth errors like an invalid
+    // application id are fixed by a restart with the right config
+    pub fn rejects_payload(&self, error: &(dyn Error + Send + Sync + 'static)) -> bool {
+        error
+            .downcast_ref::<HttpStatusError>()
+            .is_some_and(|e| matches!(e.status, 400 | 413 | 422))
+    }
+
+    // Retry-After wins over the computed backoff and is only capped by its own, larger limit
+    pub fn backoff(&self, attempt: u32, error: &(dyn Error + Send + Sync + 'static)) -> Duration {
+        if let Some(retry_after) = error
+            .downcast_ref::<HttpStatusError>()
+            .and_then(|e| e.retry_after)
+        {
+            return retry_after.min(self.max_retry_after);
+        }
+        let exponent = attempt.saturating_sub(1).min(16);
+        let backoff = self
+            .base_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        // Equal jitter keeps at least half of the backoff so retries still spread out
+        let half = backoff / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_env;
+    use serde_json::json;
+
+    fn policy(overrides: serde_json::Value) -> RetryPolicy {
+        RetryPolicy::new(&test_env("http://localhost", overrides))
+    }
+
+    fn status_error(status: u16, retry_after: Option<Duration>) -> HttpStatusError {
+        HttpStatusError { status, retry_after }
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after(""), None);
+    }
+
+    #[test]
+    fn parses_retry_after_dates() {
+        let in_a_minute = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&in_a_minute).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{:?}", delay);
+
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = policy(json!({
+            "retry_base_backoff": 500,
+            "retry_max_backoff": 3000,
+            "retry_jitter": false,
+        }));
+        let error = status_error(503, None);
+        let backoffs: Vec<u128> = (1..=5)
+            .map(|attempt| policy.backoff(attempt, &error).as_millis())
+            .collect();
+        assert_eq!(backoffs, vec![500, 1000, 2000, 3000, 3000]);
+        assert_eq!(policy.backoff(1000, &error), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_of_the_backoff() {
+        let policy = policy(json!({"retry_base_backoff": 1000, "retry_jitter": true}));
+        let error = status_error(500, None);
+        for _ in 0..100 {
+            let backoff = policy.backoff(2, &error);
+            assert!(backoff >= Duration::from_millis(1000) && backoff <= Duration::from_millis(2000));
+        }
+    }
+
+    #[test]
+    fn retry_after_is_honored_beyond_the_max_backoff() {
+        let error = status_error(429, Some(Duration::from_secs(60)));
+        let honored = policy(json!({"retry_max_backoff": 10000}));
+        assert_eq!(honored.backoff(1, &error), Duration::from_secs(60));
+
+        let error = status_error(503, Some(Duration::from_secs(3600)));
+        let capped = policy(json!({"retry_after_max": 120000}));
+        assert_eq!(capped.backoff(1, &error), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn only_rate_limits_and_server_errors_are_retried() {
+        let policy = policy(json!({}));
+        assert!(policy.is_retryable(&status_error(429, None)));
+        assert!(policy.is_retryable(&status_error(500, None)));
+        assert!(policy.is_retryable(&status_error(503, None)));
+        assert!(!policy.is_retryable(&status_error(400, None)));
+        assert!(!policy.is_retryable(&status_error(401, None)));
+        assert!(!policy.is_retryable(&std::io::Error::other("not an HTTP error")));
+    }
+
+    #[test]
+    fn only_invalid_payloads_are_rejected() {
+        let policy = policy(json!({}));
+        for status in [400, 413, 422] {
+            assert!(policy.rejects_payload(&status_error(status, None)));
+        }
+        for status in [401, 403, 429, 503] {
+            assert!(!policy.rejects_payload(&status_error(status, None)));
+        }
+        assert!(!policy.rejects_payload(&std::io::Error::other("not an HTTP error")));
+    }
+}
@@ -1,56 +1,361 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
+use crate::bot::{self, BotClassifier};
+use crate::config::{AppConfigResponse, Config, GovernanceRule, GovernanceRules, OverflowPolicy};
+use crate::governance::{self, BlockResponse};
+use crate::metrics::Metrics;
+use crate::redaction::{BodyMasker, HeaderRedactor};
+use crate::retry::{parse_retry_after, HttpStatusError, RetryPolicy};
+use crate::spool::Spool;
 use crate::utils::*;
 use log::{info, trace};
+use tracing::Instrument;
 use reqwest::header::{HeaderMap as ReqwestHeaderMap, HeaderName, HeaderValue};
 use reqwest::{Client, Method};
+use serde::de::DeserializeOwned;
 
-use crate::event::Event;
+use crate::event::{get_envoy_client_ip, Event};
 use bytes::Bytes;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch, Mutex as AsyncMutex, Notify, Semaphore};
+use tokio::task::JoinHandle;
 
-type CallbackType = Box<dyn Fn(Vec<(String, String)>, Option<Vec<u8>>) + Send>;
+// Rounds of dropping a queued event before the drop_oldest policy drops the new one instead
+const DROP_OLDEST_ATTEMPTS: usize = 16;
+
+type CallbackType = dyn Fn(Vec<(String, String)>, Option<Vec<u8>>) + Send + Sync;
 
 #[derive(Clone)]
 pub struct EventRootContext {
     pub config: Config,
     pub event_sender: mpsc::Sender<Bytes>,
     pub client: Client,
+    pub app_config: Arc<RwLock<AppConfigResponse>>,
+    pub governance_rules: Arc<RwLock<GovernanceRules>>,
+    pub bot_classifier: Arc<BotClassifier>,
+    pub header_redactor: Arc<HeaderRedactor>,
+    pub body_masker: Arc<BodyMasker>,
+    pub processor_ready: watch::Receiver<bool>,
+    pub active_streams: Arc<AtomicUsize>,
+    pub metrics: Arc<Metrics>,
+    upload_status: Arc<RwLock<UploadStatus>>,
+    // Shared with push_event so the drop_oldest policy can discard the head of the queue
+    event_receiver: Arc<AsyncMutex<mpsc::Receiver<Bytes>>>,
+    spool: Option<Arc<Spool>>,
+    spool_writer: Option<SpoolWriter>,
+    // Batches that were built but not yet accepted by Moesif, spooled if shutdown times out
+    in_flight: Arc<Mutex<BTreeMap<u64, InFlightBatch>>>,
+    next_batch_id: Arc<AtomicU64>,
+    // One permit per batch upload allowed in flight
+    upload_permits: Arc<Semaphore>,
+    shutdown_notify: Arc<Notify>,
+    processor_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+// Counts an open ext_proc stream until dropped
+pub struct ActiveStream {
+    active_streams: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.active_streams.fetch_sub(1, Ordering::SeqCst);
+        self.metrics.streams_closed.inc();
+    }
+}
+
+// Hands spilled events and failed batches to the task that writes the spool
+#[derive(Clone)]
+struct SpoolWriter {
+    events: mpsc::Sender<Bytes>,
+    requests: mpsc::Sender<SpoolRequest>,
+}
+
+enum SpoolRequest {
+    Batch(InFlightBatch),
+    // Writes the spilled events buffered so far, then acknowledges
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Clone)]
+struct InFlightBatch {
+    body: Bytes,
+    events: usize,
+}
+
+// Outcome of the most recent batch uploads, used for readiness
+#[derive(Default)]
+struct UploadStatus {
+    last_success: Option<Instant>,
+    last_failure: Option<Instant>,
 }
 
 impl EventRootContext {
     pub fn new(config: Config) -> Self {
         let client = Client::builder()
-            .timeout(Duration::from_millis(config.env.connection_timeout as u64))
+            .timeout(Duration::from_millis(config.env.connection_timeout))
             .build()
             .expect("Failed to build HTTP client");
 
         let (event_sender, event_receiver) = mpsc::channel::<Bytes>(config.env.queue_max_size);
+        let (ready_sender, processor_ready) = watch::channel(false);
+
+        let spool = config.env.spool_dir.as_ref().and_then(|dir| {
+            match Spool::open(
+                dir,
+                config.env.spool_segment_max_bytes,
+                config.env.spool_max_bytes,
+            ) {
+                Ok(spool) => Some(Arc::new(spool)),
+                Err(e) => {
+                    log::error!("Failed to open spool directory {}, spooling is disabled: {:?}", dir, e);
+                    None
+                }
+            }
+        });
+
+        let (spool_writer, spool_receivers) = match &spool {
+            Some(_) => {
+                let (events, event_receiver) = mpsc::channel(config.env.queue_max_size);
+                let (requests, request_receiver) = mpsc::channel(config.env.queue_max_size);
+                (
+                    Some(SpoolWriter { events, requests }),
+                    Some((event_receiver, request_receiver)),
+                )
+            }
+            None => (None, None),
+        };
 
         let root_context = EventRootContext {
             config: config.clone(),
-            event_sender: event_sender,
+            event_sender,
             client: client.clone(),
+            app_config: Arc::new(RwLock::new(AppConfigResponse::default())),
+            governance_rules: Arc::new(RwLock::new(GovernanceRules::default())),
+            bot_classifier: Arc::new(BotClassifier::new(config.env.bot_patterns_file.as_deref())),
+            header_redactor: Arc::new(HeaderRedactor::new(&config.env)),
+            body_masker: Arc::new(BodyMasker::new(&config.env)),
+            processor_ready,
+            active_streams: Arc::new(AtomicUsize::new(0)),
+            metrics: Arc::new(Metrics::new().expect("Failed to register metrics")),
+            upload_status: Arc::new(RwLock::new(UploadStatus::default())),
+            event_receiver: Arc::new(AsyncMutex::new(event_receiver)),
+            spool,
+            spool_writer,
+            in_flight: Arc::new(Mutex::new(BTreeMap::new())),
+            next_batch_id: Arc::new(AtomicU64::new(0)),
+            upload_permits: Arc::new(Semaphore::new(config.env.max_concurrent_uploads)),
+            shutdown_notify: Arc::new(Notify::new()),
+            processor_handle: Arc::new(Mutex::new(None)),
         };
 
+        if let (Some(spool), Some((events, requests))) = (&root_context.spool, spool_receivers) {
+            let writer_context = root_context.clone();
+            let spool = spool.clone();
+            tokio::spawn(async move {
+                writer_context.run_spool_writer(spool, events, requests).await;
+            });
+        }
+
+        // Load the app config and rules so dashboard-side settings apply from the start
+        let config_context = root_context.clone();
+        tokio::spawn(async move {
+            config_context.fetch_app_config().await;
+            config_context.fetch_governance_rules().await;
+            // Send whatever a previous run left in the spool
+            config_context.replay_spool();
+        });
+
+        // Retries the spool when no successful upload triggers a replay
+        if root_context.spool.is_some() {
+            let replay_context = root_context.clone();
+            tokio::spawn(async move {
+                let interval = Duration::from_millis(replay_context.config.env.spool_replay_interval);
+                let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                loop {
+                    ticks.tick().await;
+                    replay_context.replay_spool();
+                }
+            });
+        }
+
         let cloned_context = root_context.clone();
         // Start background task to process events
-        tokio::spawn(async move {
-            cloned_context.run_event_processor(event_receiver).await;
+        let processor_handle = tokio::spawn(async move {
+            let _ = ready_sender.send(true);
+            cloned_context.run_event_processor().await;
         });
+        if let Ok(mut handle) = root_context.processor_handle.lock() {
+            *handle = Some(processor_handle);
+        }
 
         root_context
     }
 
-    pub async fn push_event(&self, event: Event) {
+    pub fn track_stream(&self) -> ActiveStream {
+        self.active_streams.fetch_add(1, Ordering::SeqCst);
+        self.metrics.streams_opened.inc();
+        ActiveStream {
+            active_streams: self.active_streams.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    // Ready once the config is valid and the event processor runs, unless uploads keep failing
+    pub fn readiness(&self) -> Result<(), String> {
+        self.config.env.validate()?;
+        if !*self.processor_ready.borrow() {
+            return Err("event processor is not running".to_string());
+        }
+        let upload_status = self
+            .upload_status
+            .read()
+            .map_err(|_| "upload status lock poisoned".to_string())?;
+        let max_age = Duration::from_millis(self.config.env.readiness_upload_max_age);
+        match (upload_status.last_success, upload_status.last_failure) {
+            (_, None) => Ok(()),
+            (Some(success), Some(failure)) if success > failure || success.elapsed() <= max_age => {
+                Ok(())
+            }
+            _ => Err("no successful upload to Moesif recently".to_string()),
+        }
+    }
+
+    fn record_upload(&self, success: bool) {
+        if let Ok(mut upload_status) = self.upload_status.write() {
+            if success {
+                upload_status.last_success = Some(Instant::now());
+            } else {
+                upload_status.last_failure = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.event_sender.max_capacity() - self.event_sender.capacity()
+    }
+
+    // Stops the event processor after it has sent everything still queued or batched.
+    // What could not be sent before the timeout is spooled when there is a spool.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let processor_handle = match self.processor_handle.lock() {
+            Ok(mut handle) => handle.take(),
+            Err(_) => None,
+        };
+        let mut processor_handle = match processor_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        info!("Flushing queued events before shutdown.");
+        self.shutdown_notify.notify_one();
+        match tokio::time::timeout(timeout, &mut processor_handle).await {
+            Ok(_) => info!("Flushed queued events."),
+            Err(_) if self.spool_writer.is_some() => {
+                // Batches still waiting for a permit stay tracked, and the processor batches
+                // the rest of the queue the same way before it stops
+                self.upload_permits.close();
+                let _ = processor_handle.await;
+                let spooled = self.spool_unsent_batches().await;
+                log::warn!(
+                    "Timed out after {:?} flushing queued events, spooled {} unsent batches.",
+                    timeout,
+                    spooled
+                );
+            }
+            Err(_) => log::error!(
+                "Timed out after {:?} flushing queued events, remaining events are dropped.",
+                timeout
+            ),
+        }
+        self.flush_spool_writer().await;
+    }
+
+    // Spools the batches still uploading or retrying and the events left in the queue
+    async fn spool_unsent_batches(&self) -> usize {
+        let mut batches: Vec<InFlightBatch> = match self.in_flight.lock() {
+            Ok(mut in_flight) => std::mem::take(&mut *in_flight).into_values().collect(),
+            Err(_) => Vec::new(),
+        };
+
+        let mut batcher = Batcher::new(
+            self.config.env.batch_max_size,
+            self.config.env.batch_max_bytes,
+            self.config.env.batch_max_wait,
+        );
+        let mut event_receiver = self.event_receiver.lock().await;
+        while let Ok(event) = event_receiver.try_recv() {
+            if batcher.would_overflow(&event) {
+                batches.push(self.take_batch(&mut batcher).await);
+            }
+            batcher.handle_new_event(event).await;
+        }
+        if batcher.has_events() {
+            batches.push(self.take_batch(&mut batcher).await);
+        }
+
+        let spooled = batches.len();
+        for batch in batches {
+            self.spool_batch(batch).await;
+        }
+        spooled
+    }
+
+    async fn take_batch(&self, batcher: &mut Batcher) -> InFlightBatch {
+        let buffer = std::mem::take(&mut batcher.buffer);
+        batcher.reset();
+        InFlightBatch {
+            body: self.write_events_json(&buffer).await,
+            events: buffer.len(),
+        }
+    }
+
+    // Returns false if the event processor task ended before it started running
+    pub async fn wait_until_ready(&self) -> bool {
+        let mut processor_ready = self.processor_ready.clone();
+        while !*processor_ready.borrow() {
+            if processor_ready.changed().await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[tracing::instrument(name = "push_event", skip_all)]
+    pub async fn push_event(&self, mut event: Event) {
+        self.header_redactor.redact_event(&mut event);
+        self.body_masker.mask_event(&mut event);
         match serde_json::to_vec(&event) {
-            Ok(event_bytes) => {
-                // Send event to the channel, await if queue is full
-                if let Err(e) = self.event_sender.send(Bytes::from(event_bytes)).await {
+            Ok(mut event_bytes) => {
+                // An event has to fit in a batch on its own, so oversize bodies are replaced
+                if event_bytes.len() + 2 > self.config.env.batch_max_bytes {
+                    log::warn!(
+                        "Event of {} bytes exceeds batch_max_bytes, truncating its bodies.",
+                        event_bytes.len()
+                    );
+                    event.truncate_bodies();
+                    event_bytes = match serde_json::to_vec(&event) {
+                        Ok(event_bytes) if event_bytes.len() + 2 <= self.config.env.batch_max_bytes => {
+                            event_bytes
+                        }
+                        _ => {
+                            log::error!("Event still exceeds batch_max_bytes after truncation, dropping it.");
+                            self.metrics.events_dropped.with_label_values(&["oversize"]).inc();
+                            return;
+                        }
+                    };
+                }
+                if let Err(e) = self.enqueue_event(Bytes::from(event_bytes)).await {
                     log::error!("Failed to send event to queue: {:?}", e);
-                } else {
+                } else if self.config.env.log_bodies {
                     log::trace!("Event sent to queue: {:?}", event);
+                } else {
+                    log::trace!("Event sent to queue: {} {}", event.request.verb, event.request.uri);
                 }
             },
             Err(e) => {
@@ -59,63 +364,535 @@ impl EventRootContext {
         }
     }
 
+    // A full queue spills to the spool when there is one, otherwise the overflow policy applies
+    async fn enqueue_event(&self, event_bytes: Bytes) -> Result<(), &'static str> {
+        let mut event_bytes = match self.event_sender.try_send(event_bytes) {
+            Ok(()) => {
+                self.metrics.events_enqueued.inc();
+                return Ok(());
+            }
+            Err(TrySendError::Closed(_)) => return Err("event queue is closed"),
+            Err(TrySendError::Full(event_bytes)) => event_bytes,
+        };
+        // The overflow policy only applies once the spool writer cannot keep up either
+        if let Some(spool_writer) = &self.spool_writer {
+            event_bytes = match spool_writer.events.try_send(event_bytes) {
+                Ok(()) => {
+                    trace!("Event queue is full, spooling event.");
+                    return Ok(());
+                }
+                Err(TrySendError::Full(event_bytes)) | Err(TrySendError::Closed(event_bytes)) => {
+                    event_bytes
+                }
+            };
+        }
+        match self.config.env.overflow_policy {
+            OverflowPolicy::Block => {
+                self.event_sender
+                    .send(event_bytes)
+                    .await
+                    .map_err(|_| "event queue is closed")?;
+                self.metrics.events_enqueued.inc();
+                Ok(())
+            }
+            OverflowPolicy::DropNewest => {
+                self.record_overflow("drop_newest");
+                Ok(())
+            }
+            // Never waits for the receiver lock. The processor only holds it while waiting for
+            // events, and then takes one from the full queue right away. Should the queue stay
+            // full anyway, the new event is dropped instead of retrying forever.
+            OverflowPolicy::DropOldest => {
+                for _ in 0..DROP_OLDEST_ATTEMPTS {
+                    if let Ok(mut event_receiver) = self.event_receiver.try_lock() {
+                        if event_receiver.try_recv().is_ok() {
+                            self.record_overflow("drop_oldest");
+                        }
+                    }
+                    event_bytes = match self.event_sender.try_send(event_bytes) {
+                        Ok(()) => {
+                            self.metrics.events_enqueued.inc();
+                            return Ok(());
+                        }
+                        Err(TrySendError::Closed(_)) => return Err("event queue is closed"),
+                        Err(TrySendError::Full(event_bytes)) => {
+                            tokio::task::yield_now().await;
+                            event_bytes
+                        }
+                    };
+                }
+                trace!("Event queue stayed full, dropping the new event.");
+                self.record_overflow("drop_oldest");
+                Ok(())
+            }
+        }
+    }
+
+    fn record_overflow(&self, policy: &str) {
+        let counter = self.metrics.events_dropped.with_label_values(&[policy]);
+        counter.inc();
+        let dropped = counter.get();
+        if dropped == 1 || dropped.is_multiple_of(1000) {
+            log::warn!(
+                "Event queue is full, {} events dropped so far by the {} policy.",
+                dropped,
+                policy
+            );
+        }
+    }
+
+
+    pub fn config_etag(&self) -> Option<String> {
+        self.app_config
+            .read()
+            .ok()
+            .and_then(|app_config| app_config.e_tag.clone())
+    }
+
+    pub async fn fetch_app_config(&self) {
+        self.fetch_config(
+            "/v1/config",
+            "app_config",
+            &self.app_config,
+            |mut app_config: AppConfigResponse, headers| {
+                app_config.e_tag = get_header(headers, "X-Moesif-Config-Etag");
+                app_config.compile_regexes();
+                info!("Loaded app config with eTag {:?}", app_config.e_tag);
+                app_config
+            },
+        )
+        .await;
+    }
+
+    pub async fn fetch_governance_rules(&self) {
+        self.fetch_config(
+            "/v1/rules",
+            "governance_rules",
+            &self.governance_rules,
+            |mut rules: Vec<GovernanceRule>, headers| {
+                rules.iter_mut().for_each(GovernanceRule::compile_regexes);
+                let e_tag = get_header(headers, "X-Moesif-Rules-Etag");
+                info!("Loaded {} governance rules with eTag {:?}", rules.len(), e_tag);
+                GovernanceRules { rules, e_tag }
+            },
+        )
+        .await;
+    }
+
+    // GETs a config, stores what `prepare` builds from it and counts the refresh under `name`
+    async fn fetch_config<T, S>(
+        &self,
+        path: &str,
+        name: &'static str,
+        store: &Arc<RwLock<S>>,
+        prepare: impl Fn(T, &Vec<(String, String)>) -> S + Send + Sync + 'static,
+    ) where
+        T: DeserializeOwned,
+        S: Send + Sync + 'static,
+    {
+        let store = store.clone();
+        let metrics = self.metrics.clone();
+        let description = name.replace('_', " ");
+        let callback_description = description.clone();
+
+        if let Err(e) = self
+            .dispatch_http_request(
+                "GET",
+                path,
+                Bytes::new(),
+                &move |headers, body| {
+                    let description = &callback_description;
+                    let body = body.unwrap_or_default();
+                    match serde_json::from_slice::<T>(&body) {
+                        Ok(config) => {
+                            let config = prepare(config, &headers);
+                            match store.write() {
+                                Ok(mut current) => {
+                                    *current = config;
+                                    metrics.config_refreshes.with_label_values(&[name]).inc();
+                                }
+                                Err(e) => log::error!("Failed to store {}: {:?}", description, e),
+                            }
+                        }
+                        Err(e) => {
+                            log::error!(
+                                "Failed to parse {}: {:?}, body: {}",
+                                description,
+                                e,
+                                String::from_utf8_lossy(&body)
+                            );
+                        }
+                    }
+                },
+            )
+            .await
+        {
+            log::error!("Failed to fetch {}: {:?}", description, e);
+        }
+    }
+
+    pub fn rules_etag(&self) -> Option<String> {
+        self.governance_rules
+            .read()
+            .ok()
+            .and_then(|governance_rules| governance_rules.e_tag.clone())
+    }
+
+    pub fn evaluate_governance(&self, event: &Event) -> Option<BlockResponse> {
+        let app_config = self.app_config.read().ok()?;
+        // Not the logged ip_address, which prefers headers the client can set
+        let client_ip =
+            get_envoy_client_ip(&event.request.headers, self.config.env.xff_num_trusted_hops);
+        if let Some(ip_address) = &client_ip {
+            let blocked = governance::evaluate_blocked_ip(
+                &app_config.ip_addresses_blocked_by_name,
+                ip_address,
+                &self.config.env,
+            );
+            if blocked.is_some() {
+                return blocked;
+            }
+        }
+        if app_config.block_bot_traffic {
+            if let Some(bot_name) = bot::get_bot_name(event) {
+                return Some(governance::evaluate_bot(bot_name, &self.config.env));
+            }
+        }
+        let governance_rules = self.governance_rules.read().ok()?;
+        governance::evaluate(&app_config, &governance_rules.rules, event)
+    }
 
-    async fn run_event_processor(&self, mut event_receiver: mpsc::Receiver<Bytes>) {
+    async fn run_event_processor(&self) {
         let mut batcher = Batcher::new(
             self.config.env.batch_max_size,
+            self.config.env.batch_max_bytes,
             self.config.env.batch_max_wait,
         );
 
         loop {
+            // Held only while waiting for events, never while sending a batch
+            let mut event_receiver = self.event_receiver.lock().await;
             tokio::select! {
                 Some(event) = event_receiver.recv() => {
-                    batcher.handle_new_event(event).await;
-                    if batcher.should_flush() {
-                        self.flush_buffer(&mut batcher).await;
-                    }
+                    drop(event_receiver);
+                    self.add_to_batch(&mut batcher, event).await;
                 },
                 _ = tokio::time::sleep(batcher.calculate_timeout()), if batcher.has_events() => {
+                    drop(event_receiver);
+                    self.flush_buffer(&mut batcher).await;
+                },
+                _ = self.shutdown_notify.notified() => {
+                    // Stop accepting events, then send whatever is left in the queue
+                    event_receiver.close();
+                    while let Some(event) = event_receiver.recv().await {
+                        self.add_to_batch(&mut batcher, event).await;
+                    }
                     self.flush_buffer(&mut batcher).await;
+                    // Wait for the uploads still in flight
+                    let _ = self
+                        .upload_permits
+                        .acquire_many(self.config.env.max_concurrent_uploads as u32)
+                        .await;
+                    return;
                 },
             }
         }
     }
 
-    async fn flush_buffer(&self, batcher: &mut Batcher) {
-        self.send_batch(&batcher.buffer).await;
-        batcher.reset();
+    // Flushes before the event would make the batch too big, and again once a limit is reached.
+    // The event joins the next batch before waiting for an upload permit, so it is never
+    // held where shutdown cannot find it.
+    async fn add_to_batch(&self, batcher: &mut Batcher, event: Bytes) {
+        let full_batch = if batcher.would_overflow(&event) {
+            Some(self.track_batch(batcher).await)
+        } else {
+            None
+        };
+        batcher.handle_new_event(event).await;
+        if let Some((batch_id, batch)) = full_batch {
+            self.upload_batch(batch_id, batch).await;
+        }
+        if batcher.should_flush() {
+            self.flush_buffer(batcher).await;
+        }
     }
 
-    async fn send_batch(&self, buffer: &Vec<Bytes>) {
-        if buffer.is_empty() {
+    #[tracing::instrument(name = "flush_buffer", skip_all, fields(events = batcher.buffer.len()))]
+    async fn flush_buffer(&self, batcher: &mut Batcher) {
+        if batcher.buffer.is_empty() {
+            batcher.reset();
             return;
         }
+        let (batch_id, batch) = self.track_batch(batcher).await;
+        self.upload_batch(batch_id, batch).await;
+    }
 
-        let body = self.write_events_json(buffer).await;
-        info!("Posting {} events.", buffer.len());
+    // Takes the batch and tracks it until it is uploaded, so that shutdown can spool it
+    async fn track_batch(&self, batcher: &mut Batcher) -> (u64, InFlightBatch) {
+        let batch = self.take_batch(batcher).await;
+        let batch_id = self.next_batch_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.insert(batch_id, batch.clone());
+        }
+        (batch_id, batch)
+    }
 
-        if let Err(e) = self
-            .dispatch_http_request(
-                "POST",
-                "/v1/events/batch",
-                body,
-                Box::new(|headers, _| {
-                    let config_etag = get_header(&headers, "X-Moesif-Config-Etag");
-                    let rules_etag = get_header(&headers, "X-Moesif-Rules-Etag");
-                    trace!(
-                        "Event Response eTags: config={:?} rules={:?}",
-                        config_etag,
-                        rules_etag
-                    );
-                }),
-            )
-            .await
-        {
-            log::error!("Failed to dispatch HTTP request: {:?}", e);
+    // Uploads the batch in the background, waiting only when all upload permits are taken.
+    // Once shutdown closes the permits the batch stays tracked for the spool instead.
+    async fn upload_batch(&self, batch_id: u64, batch: InFlightBatch) {
+        let permit = match self.upload_permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => {
+                trace!("Upload permits are closed, leaving {} events to be spooled", batch.events);
+                return;
+            }
+        };
+        let context = self.clone();
+        tokio::spawn(
+            async move {
+                context.send_batch(batch).await;
+                if let Ok(mut in_flight) = context.in_flight.lock() {
+                    in_flight.remove(&batch_id);
+                }
+                drop(permit);
+            }
+            .in_current_span(),
+        );
+    }
+
+    async fn send_batch(&self, batch: InFlightBatch) {
+        info!("Posting {} events.", batch.events);
+        self.metrics.batch_events.observe(batch.events as f64);
+        self.metrics.batch_bytes.observe(batch.body.len() as f64);
+
+        let context = self.clone();
+        let callback = move |headers: Vec<(String, String)>, _| {
+            let config_etag = get_header(&headers, "X-Moesif-Config-Etag");
+            let rules_etag = get_header(&headers, "X-Moesif-Rules-Etag");
+            trace!(
+                "Event Response eTags: config={:?} rules={:?}",
+                config_etag,
+                rules_etag
+            );
+            if config_etag.is_some() && config_etag != context.config_etag() {
+                info!("App config eTag changed, refreshing app config.");
+                let context = context.clone();
+                tokio::spawn(async move {
+                    context.fetch_app_config().await;
+                });
+            }
+            if rules_etag.is_some() && rules_etag != context.rules_etag() {
+                info!("Governance rules eTag changed, refreshing governance rules.");
+                let context = context.clone();
+                tokio::spawn(async move {
+                    context.fetch_governance_rules().await;
+                });
+            }
+        };
+
+        match self.post_batch(batch.body.clone(), &callback).await {
+            Ok(()) => self.replay_spool(),
+            Err(e) => {
+                let retryable = RetryPolicy::new(&self.config.env).is_retryable(e.as_ref());
+                if retryable && self.spool_writer.is_some() {
+                    log::warn!("Spooling {} events after failing to post them: {}", batch.events, e);
+                    self.spool_batch(batch).await;
+                } else {
+                    log::error!("Dropping {} events after failing to post them: {}", batch.events, e);
+                    self.metrics
+                        .events_dropped
+                        .with_label_values(&["upload_failed"])
+                        .inc_by(batch.events as u64);
+                }
+            }
+        }
+    }
+
+    // Posts one batch, retrying per the retry policy, and returns the last error once it gives up
+    async fn post_batch(
+        &self,
+        body: Bytes,
+        callback: &CallbackType,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let policy = RetryPolicy::new(&self.config.env);
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .dispatch_http_request("POST", "/v1/events/batch", body.clone(), callback)
+                .await;
+            let e = match result {
+                Ok(_) => {
+                    self.record_upload(true);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            if !policy.is_retryable(e.as_ref()) || attempt >= policy.max_attempts {
+                trace!("Giving up posting events after {} attempt(s)", attempt);
+                self.record_upload(false);
+                return Err(e);
+            }
+            let backoff = policy.backoff(attempt, e.as_ref());
+            log::warn!(
+                "Failed to post events on attempt {}: {}, retrying in {:?}.",
+                attempt,
+                e,
+                backoff
+            );
+            self.metrics.upload_retries.inc();
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn spool_batch(&self, batch: InFlightBatch) {
+        if let Some(spool_writer) = &self.spool_writer {
+            if let Err(e) = spool_writer.requests.send(SpoolRequest::Batch(batch)).await {
+                log::error!("Spool writer stopped, {} events are dropped.", e.0.events());
+            }
+        }
+    }
+
+    // Waits until the spool writer has written the spilled events it buffered
+    async fn flush_spool_writer(&self) {
+        if let Some(spool_writer) = &self.spool_writer {
+            let (done, flushed) = oneshot::channel();
+            if spool_writer.requests.send(SpoolRequest::Flush(done)).await.is_ok() {
+                let _ = flushed.await;
+            }
+        }
+    }
+
+    // Writes failed batches as they come and batches the events spilled from a full queue
+    // with the same limits as uploads, so they are replayed as regular batches
+    async fn run_spool_writer(
+        &self,
+        spool: Arc<Spool>,
+        mut events: mpsc::Receiver<Bytes>,
+        mut requests: mpsc::Receiver<SpoolRequest>,
+    ) {
+        let mut batcher = Batcher::new(
+            self.config.env.batch_max_size,
+            self.config.env.batch_max_bytes,
+            self.config.env.batch_max_wait,
+        );
+        loop {
+            tokio::select! {
+                Some(event) = events.recv() => self.add_to_spool_batch(&spool, &mut batcher, event).await,
+                request = requests.recv() => match request {
+                    Some(SpoolRequest::Batch(batch)) => self.write_to_spool(&spool, batch).await,
+                    Some(SpoolRequest::Flush(done)) => {
+                        // Spilled events that are still in the channel belong to this flush too
+                        while let Ok(event) = events.try_recv() {
+                            self.add_to_spool_batch(&spool, &mut batcher, event).await;
+                        }
+                        if batcher.has_events() {
+                            let batch = self.take_batch(&mut batcher).await;
+                            self.write_to_spool(&spool, batch).await;
+                        }
+                        let _ = done.send(());
+                    }
+                    None => return,
+                },
+                _ = tokio::time::sleep(batcher.calculate_timeout()), if batcher.has_events() => {
+                    let batch = self.take_batch(&mut batcher).await;
+                    self.write_to_spool(&spool, batch).await;
+                },
+            }
+        }
+    }
+
+    async fn add_to_spool_batch(&self, spool: &Arc<Spool>, batcher: &mut Batcher, event: Bytes) {
+        if batcher.would_overflow(&event) {
+            let batch = self.take_batch(batcher).await;
+            self.write_to_spool(spool, batch).await;
+        }
+        batcher.handle_new_event(event).await;
+        if batcher.should_flush() {
+            let batch = self.take_batch(batcher).await;
+            self.write_to_spool(spool, batch).await;
+        }
+    }
+
+    async fn write_to_spool(&self, spool: &Arc<Spool>, batch: InFlightBatch) {
+        let body = batch.body;
+        match spool_io(spool, move |spool| spool.write_batch(&body)).await {
+            Ok(()) => self.metrics.events_spooled.inc_by(batch.events as u64),
+            Err(e) => {
+                log::error!("Failed to spool {} events, they are dropped: {:?}", batch.events, e);
+                self.metrics
+                    .events_dropped
+                    .with_label_values(&["spool_failed"])
+                    .inc_by(batch.events as u64);
+            }
         }
     }
 
-    async fn write_events_json(&self, events: &Vec<Bytes>) -> Bytes {
+    // Sends spooled batches oldest first in the background, stopping at the first failure.
+    // Batches whose payload Moesif rejects are dropped so they do not hold up the rest of the spool.
+    fn replay_spool(&self) {
+        let spool = match &self.spool {
+            Some(spool) if spool.has_batches() => spool.clone(),
+            _ => return,
+        };
+        let context = self.clone();
+        tokio::spawn(async move {
+            let _guard = match spool.start_replay() {
+                Some(guard) => guard,
+                None => return,
+            };
+            let callback = |_: Vec<(String, String)>, _| {};
+            let policy = RetryPolicy::new(&context.config.env);
+            loop {
+                let segment = match spool_io(&spool, |spool| spool.oldest_segment()).await {
+                    Ok(Some(segment)) => segment,
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::error!("Failed to read spooled events: {:?}", e);
+                        return;
+                    }
+                };
+                info!(
+                    "Replaying {} spooled batches from {}",
+                    segment.batches.len(),
+                    segment.path.display()
+                );
+                let segment = Arc::new(segment);
+                for (i, batch) in segment.batches.iter().enumerate() {
+                    let e = match context.post_batch(batch.clone(), &callback).await {
+                        Ok(()) => continue,
+                        Err(e) => e,
+                    };
+                    if policy.rejects_payload(e.as_ref()) {
+                        let events = spooled_batch_events(batch);
+                        log::error!("Dropping {} spooled events after Moesif rejected them: {}", events, e);
+                        context
+                            .metrics
+                            .events_dropped
+                            .with_label_values(&["spool_rejected"])
+                            .inc_by(events as u64);
+                        continue;
+                    }
+                    log::warn!("Stopped replaying spooled events: {}", e);
+                    let retained = segment.clone();
+                    let result = spool_io(&spool, move |spool| {
+                        spool.retain_batches(&retained, &retained.batches[i..])
+                    });
+                    if let Err(e) = result.await {
+                        log::error!("Failed to update spooled segment: {:?}", e);
+                    }
+                    return;
+                }
+                let completed = segment.clone();
+                if let Err(e) = spool_io(&spool, move |spool| spool.complete_segment(&completed)).await {
+                    log::error!("Failed to remove spooled segment: {:?}", e);
+                    return;
+                }
+            }
+        });
+    }
+
+    async fn write_events_json(&self, events: &[Bytes]) -> Bytes {
         log::trace!("Entering write_events_json with {} events.", events.len());
 
         // Calculate the total size needed for all event bytes
@@ -141,7 +918,7 @@ impl EventRootContext {
 
             log::trace!(
                 "Adding event to JSON array: {}",
-                String::from_utf8_lossy(event_bytes)
+                loggable_body(event_bytes, self.config.env.log_bodies)
             );
         }
 
@@ -150,17 +927,18 @@ impl EventRootContext {
         log::trace!(
             "Final JSON array being sent, length {}: {}",
             event_json_array.len(),
-            String::from_utf8_lossy(&event_json_array)
+            loggable_body(&event_json_array, self.config.env.log_bodies)
         );
         event_json_array.into() // Return as Bytes
     }
 
+    #[tracing::instrument(name = "dispatch_http_request", skip(self, body, callback), fields(status))]
     async fn dispatch_http_request(
         &self,
         method: &str,
         path: &str,
         body: Bytes,
-        callback: CallbackType,
+        callback: &CallbackType,
     ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
         log::trace!("Entering dispatch_http_request.");
 
@@ -174,12 +952,16 @@ impl EventRootContext {
             HeaderName::from_static("content-type"),
             HeaderValue::from_static("application/json"),
         );
+        // Sensitive values are masked when the headers are logged
+        let mut application_id = HeaderValue::from_str(self.config.env.moesif_application_id.as_str())?;
+        application_id.set_sensitive(true);
         headers.insert(
             HeaderName::from_static("x-moesif-application-id"),
-            HeaderValue::from_str(&self.config.env.moesif_application_id)?,
+            application_id,
         );
 
-        let curl_cmd = generate_curl_command(method.as_str(), &url, &headers, Some(&body));
+        let curl_body = Some(&body).filter(|_| self.config.env.log_bodies);
+        let curl_cmd = generate_curl_command(method.as_str(), &url, &headers, curl_body);
         log::trace!("Equivalent curl command:\n{}", curl_cmd);
 
         log::trace!(
@@ -187,19 +969,60 @@ impl EventRootContext {
             method,
             url,
             headers,
-            std::str::from_utf8(&body).unwrap_or_default()
+            loggable_body(&body, self.config.env.log_bodies)
         );
 
+        // Only event batches are compressed, the config and rules requests have no body
+        let compress = self.config.env.gzip_enabled
+            && method == Method::POST
+            && body.len() >= self.config.env.gzip_min_size;
+        let body = if compress {
+            match gzip_compress(&body, self.config.env.gzip_level) {
+                Ok(compressed) => {
+                    trace!("Compressed body from {} to {} bytes", body.len(), compressed.len());
+                    headers.insert(
+                        HeaderName::from_static("content-encoding"),
+                        HeaderValue::from_static("gzip"),
+                    );
+                    Bytes::from(compressed)
+                }
+                Err(e) => {
+                    log::warn!("Failed to gzip body, sending it uncompressed: {:?}", e);
+                    body
+                }
+            }
+        } else {
+            body
+        };
+
+        let timer = self
+            .metrics
+            .api_request_duration
+            .with_label_values(&[path])
+            .start_timer();
         let response = self
             .client
             .request(method, &url)
             .headers(headers)
             .body(body)
             .send()
-            .await?;
+            .await;
+        timer.observe_duration();
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                self.metrics.api_responses.with_label_values(&[path, "error"]).inc();
+                return Err(Box::new(e));
+            }
+        };
 
         let status = response.status();
         log::trace!("Received response with status: {}", status);
+        tracing::Span::current().record("status", status.as_u16());
+        self.metrics
+            .api_responses
+            .with_label_values(&[path, status.as_str()])
+            .inc();
 
         let headers: Vec<(String, String)> = response
             .headers()
@@ -207,6 +1030,15 @@ impl EventRootContext {
             .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
             .collect();
 
+        if !status.is_success() {
+            let retry_after = get_header(&headers, "Retry-After")
+                .and_then(|value| parse_retry_after(&value));
+            return Err(Box::new(HttpStatusError {
+                status: status.as_u16(),
+                retry_after,
+            }));
+        }
+
         let body = response.bytes().await.ok();
 
         // Call the provided callback with the headers and response body
@@ -218,19 +1050,50 @@ impl EventRootContext {
     }
 }
 
+impl SpoolRequest {
+    fn events(&self) -> usize {
+        match self {
+            SpoolRequest::Batch(batch) => batch.events,
+            SpoolRequest::Flush(_) => 0,
+        }
+    }
+}
+
+// A spooled line that is not a JSON array still counts as one dropped event
+fn spooled_batch_events(batch: &[u8]) -> usize {
+    serde_json::from_slice::<Vec<serde::de::IgnoredAny>>(batch).map_or(1, |events| events.len())
+}
+
+// Spool file I/O runs on the blocking pool rather than on the tasks serving ext_proc streams
+async fn spool_io<T, F>(spool: &Arc<Spool>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Spool) -> io::Result<T> + Send + 'static,
+{
+    let spool = spool.clone();
+    tokio::task::spawn_blocking(move || f(&spool))
+        .await
+        .map_err(io::Error::other)?
+}
+
 struct Batcher {
     buffer: Vec<Bytes>,
+    // Size of the JSON array the buffer serializes to
+    buffer_bytes: usize,
     first_event_time: Option<tokio::time::Instant>,
     max_size: usize,
+    max_bytes: usize,
     max_wait: u64,
 }
 
 impl Batcher {
-    fn new(max_size: usize, max_wait: u64) -> Self {
+    fn new(max_size: usize, max_bytes: usize, max_wait: u64) -> Self {
         Batcher {
             buffer: Vec::new(),
+            buffer_bytes: 2,
             first_event_time: None,
             max_size,
+            max_bytes,
             max_wait,
         }
     }
@@ -252,11 +1115,18 @@ impl Batcher {
         if self.first_event_time.is_none() {
             self.first_event_time = Some(tokio::time::Instant::now());
         }
+        // One byte for the comma separating it from the previous event
+        self.buffer_bytes += event.len() + usize::from(!self.buffer.is_empty());
         self.buffer.push(event);
     }
 
     fn should_flush(&self) -> bool {
-        self.buffer.len() >= self.max_size
+        self.buffer.len() >= self.max_size || self.buffer_bytes >= self.max_bytes
+    }
+
+    // True when adding the event would push a non-empty batch past max_bytes
+    fn would_overflow(&self, event: &Bytes) -> bool {
+        !self.buffer.is_empty() && self.buffer_bytes + event.len() + 1 > self.max_bytes
     }
 
     fn has_events(&self) -> bool {
@@ -266,5 +1136,603 @@ impl Batcher {
     fn reset(&mut self) {
         self.first_event_time = None;
         self.buffer.clear();
+        self.buffer_bytes = 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ResponseInfo;
+    use crate::test_utils::{test_env, wait_for, MockResponse, MockServer, TempDir};
+    use std::collections::HashMap;
+    use serde_json::json;
+    use std::io::Read;
+
+    fn test_context(server: &MockServer, overrides: serde_json::Value) -> EventRootContext {
+        EventRootContext::new(Config {
+            env: test_env(&server.base_uri, overrides),
+        })
+    }
+
+    #[tokio::test]
+    async fn loads_app_config_at_startup() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/config",
+            MockResponse::ok(r#"{"org_id":"org","app_id":"app","sample_rate":40}"#)
+                .header("X-Moesif-Config-Etag", "etag-1"),
+        );
+
+        let context = test_context(&server, json!({}));
+        wait_for("the app config", || context.config_etag().is_some()).await;
+
+        let app_config = context.app_config.read().unwrap();
+        assert_eq!(app_config.e_tag.as_deref(), Some("etag-1"));
+        assert_eq!(app_config.sample_rate, 40);
+        assert_eq!(app_config.app_id, "app");
+        let requests = server.take_requests("/v1/config");
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].headers["x-moesif-application-id"], "test-application-id");
+    }
+
+    #[tokio::test]
+    async fn loads_governance_rules_at_startup() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/rules",
+            MockResponse::ok(
+                r#"[{"_id":"regex-rule","type":"regex","block":true,
+                    "regex_config":[{"conditions":[{"path":"request.route","value":"^/admin"}]}],
+                    "response":{"status":403}}]"#,
+            )
+            .header("X-Moesif-Rules-Etag", "rules-1"),
+        );
+
+        let context = test_context(&server, json!({}));
+        wait_for("the governance rules", || context.rules_etag().is_some()).await;
+
+        assert_eq!(context.rules_etag().as_deref(), Some("rules-1"));
+        let refreshes = context.metrics.config_refreshes.with_label_values(&["governance_rules"]);
+        assert_eq!(refreshes.get(), 1);
+        // The rule's regexes are compiled, so it blocks matching requests
+        let mut event = Event::new();
+        event.request.uri = "/admin/users".to_string();
+        let block_response = context.evaluate_governance(&event).unwrap();
+        assert_eq!(block_response.blocked_by, "regex-rule");
+        assert!(context.evaluate_governance(&Event::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn blocks_the_address_envoy_saw_rather_than_client_headers() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/config",
+            MockResponse::ok(r#"{"ip_addresses_blocked_by_name":{"203.0.113.0/24":"Blocked range"}}"#)
+                .header("X-Moesif-Config-Etag", "etag-1"),
+        );
+        let context = test_context(&server, json!({}));
+        wait_for("the app config", || context.config_etag().is_some()).await;
+
+        let mut event = Event::new();
+        event.request.set_headers(HashMap::from([
+            ("x-client-ip".to_string(), "192.0.2.1".to_string()),
+            ("x-forwarded-for".to_string(), "192.0.2.1, 203.0.113.7".to_string()),
+        ]));
+        assert_eq!(event.request.ip_address.as_deref(), Some("192.0.2.1"));
+        let block_response = context.evaluate_governance(&event).unwrap();
+        assert_eq!(block_response.blocked_by, "Blocked range");
+
+        event.request.set_headers(HashMap::from([(
+            "x-forwarded-for".to_string(),
+            "203.0.113.7, 192.0.2.1".to_string(),
+        )]));
+        assert!(context.evaluate_governance(&event).is_none());
+    }
+
+    #[tokio::test]
+    async fn refetches_governance_rules_when_batch_etag_changes() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/rules",
+            MockResponse::ok("[]").header("X-Moesif-Rules-Etag", "rules-1"),
+        );
+        let context = test_context(&server, json!({"batch_max_wait": 20}));
+        wait_for("the governance rules", || context.rules_etag().is_some()).await;
+
+        server.respond(
+            "/v1/rules",
+            MockResponse::ok(r#"[{"_id":"user-rule","type":"user","block":true}]"#)
+                .header("X-Moesif-Rules-Etag", "rules-2"),
+        );
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").header("X-Moesif-Rules-Etag", "rules-2"),
+        );
+        context.push_event(Event::new()).await;
+        wait_for("the new governance rules", || {
+            context.rules_etag().as_deref() == Some("rules-2")
+        })
+        .await;
+
+        assert_eq!(server.request_count("/v1/rules"), 2);
+        let governance_rules = context.governance_rules.read().unwrap();
+        assert_eq!(governance_rules.rules[0].id, "user-rule");
+    }
+
+    #[tokio::test]
+    async fn refetches_app_config_when_batch_etag_changes() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/config",
+            MockResponse::ok(r#"{"sample_rate":100}"#).header("X-Moesif-Config-Etag", "etag-1"),
+        );
+        let context = test_context(&server, json!({"batch_max_wait": 20}));
+        wait_for("the app config", || context.config_etag().is_some()).await;
+
+        // An unchanged eTag does not trigger a fetch
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").header("X-Moesif-Config-Etag", "etag-1"),
+        );
+        context.push_event(Event::new()).await;
+        wait_for("the first batch", || server.request_count("/v1/events/batch") == 1).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.request_count("/v1/config"), 1);
+        let batch = &server.take_requests("/v1/events/batch")[0];
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&batch.body).unwrap();
+        assert_eq!(events.len(), 1);
+
+        server.respond(
+            "/v1/config",
+            MockResponse::ok(r#"{"sample_rate":25}"#).header("X-Moesif-Config-Etag", "etag-2"),
+        );
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").header("X-Moesif-Config-Etag", "etag-2"),
+        );
+        context.push_event(Event::new()).await;
+        wait_for("the new app config", || {
+            context.config_etag().as_deref() == Some("etag-2")
+        })
+        .await;
+
+        assert_eq!(server.request_count("/v1/config"), 2);
+        assert_eq!(context.app_config.read().unwrap().sample_rate, 25);
+    }
+
+    #[tokio::test]
+    async fn gzips_event_batches_only() {
+        let server = MockServer::start();
+        let context = test_context(
+            &server,
+            json!({"batch_max_wait": 20, "gzip_enabled": true, "gzip_min_size": 0}),
+        );
+        let mut event = Event::new();
+        event.request.uri = "/items".to_string();
+        context.push_event(event).await;
+        wait_for("the batch", || server.request_count("/v1/events/batch") == 1).await;
+
+        let batch = &server.take_requests("/v1/events/batch")[0];
+        assert_eq!(batch.headers["content-encoding"], "gzip");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(batch.body.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        let events: Vec<serde_json::Value> = serde_json::from_str(&decoded).unwrap();
+        assert_eq!(events[0]["request"]["uri"], "/items");
+
+        let config_request = &server.take_requests("/v1/config")[0];
+        assert!(!config_request.headers.contains_key("content-encoding"));
+        assert!(config_request.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_redacted_headers() {
+        let server = MockServer::start();
+        let context = test_context(&server, json!({"batch_max_wait": 20}));
+        let mut event = Event::new();
+        event.request.headers = HashMap::from([
+            ("authorization".to_string(), "Bearer abc".to_string()),
+            ("accept".to_string(), "*/*".to_string()),
+        ]);
+        let mut response = ResponseInfo::new();
+        response.headers = HashMap::from([
+            ("set-cookie".to_string(), "session=1".to_string()),
+            ("content-type".to_string(), "application/json".to_string()),
+        ]);
+        event.response = Some(response);
+        context.push_event(event).await;
+        wait_for("the batch", || server.request_count("/v1/events/batch") == 1).await;
+
+        let batch = &server.take_requests("/v1/events/batch")[0];
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&batch.body).unwrap();
+        assert_eq!(events[0]["request"]["headers"]["authorization"], "*****");
+        assert_eq!(events[0]["request"]["headers"]["accept"], "*/*");
+        assert_eq!(events[0]["response"]["headers"]["set-cookie"], "*****");
+        assert_eq!(events[0]["response"]["headers"]["content-type"], "application/json");
+    }
+
+    fn spooled_events(context: &EventRootContext) -> Vec<usize> {
+        let spool = context.spool.as_ref().unwrap();
+        let mut batches = Vec::new();
+        while let Some(segment) = spool.oldest_segment().unwrap() {
+            for batch in &segment.batches {
+                let events: Vec<serde_json::Value> = serde_json::from_slice(batch).unwrap();
+                batches.push(events.len());
+            }
+            spool.complete_segment(&segment).unwrap();
+        }
+        batches
+    }
+
+    #[tokio::test]
+    async fn spools_events_overflowing_the_queue_in_batches() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").delay(Duration::from_secs(30)),
+        );
+        let spool_dir = TempDir::new();
+        let context = test_context(
+            &server,
+            json!({
+                "spool_dir": spool_dir.path(),
+                "queue_max_size": 4,
+                "max_concurrent_uploads": 1,
+                "batch_max_wait": 50,
+            }),
+        );
+
+        // The first batch stays in flight and the second waits for its upload permit
+        context.push_event(Event::new()).await;
+        wait_for("the first batch", || server.request_count("/v1/events/batch") == 1).await;
+        context.push_event(Event::new()).await;
+        wait_for("the blocked upload", || context.in_flight.lock().unwrap().len() == 2).await;
+
+        // Four events fill the queue, the rest overflow to the spool
+        for _ in 0..8 {
+            context.push_event(Event::new()).await;
+        }
+        wait_for("the spooled events", || context.metrics.events_spooled.get() == 4).await;
+
+        assert_eq!(spooled_events(&context), vec![4]);
+    }
+
+    #[tokio::test]
+    async fn spools_unsent_batches_when_shutdown_times_out() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").delay(Duration::from_secs(30)),
+        );
+        let spool_dir = TempDir::new();
+        let context = test_context(
+            &server,
+            json!({
+                "spool_dir": spool_dir.path(),
+                "max_concurrent_uploads": 1,
+                "batch_max_wait": 20,
+            }),
+        );
+
+        context.push_event(Event::new()).await;
+        wait_for("the first batch", || server.request_count("/v1/events/batch") == 1).await;
+        context.push_event(Event::new()).await;
+        context.push_event(Event::new()).await;
+        context.shutdown(Duration::from_millis(200)).await;
+
+        assert_eq!(context.metrics.events_spooled.get(), 3);
+        let mut batches = spooled_events(&context);
+        batches.sort();
+        assert_eq!(batches, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn spools_the_event_added_while_waiting_for_an_upload_permit() {
+        let server = MockServer::start();
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").delay(Duration::from_secs(30)),
+        );
+        let spool_dir = TempDir::new();
+        // Room for one event per batch, so every event after the first overflows the batch
+        let event_bytes = serde_json::to_vec(&numbered_event(0)).unwrap().len();
+        let context = test_context(
+            &server,
+            json!({
+                "spool_dir": spool_dir.path(),
+                "max_concurrent_uploads": 1,
+                "batch_max_wait": 10_000,
+                "batch_max_bytes": event_bytes * 3 / 2 + 2,
+            }),
+        );
+
+        for n in 0..3 {
+            context.push_event(numbered_event(n)).await;
+        }
+        // The first batch is in flight and the second waits for its permit
+        wait_for("the blocked upload", || context.in_flight.lock().unwrap().len() == 2).await;
+        context.push_event(numbered_event(3)).await;
+        context.shutdown(Duration::from_millis(200)).await;
+
+        assert_eq!(context.metrics.events_spooled.get(), 4);
+        assert_eq!(spooled_events(&context), vec![1, 1, 1, 1]);
+    }
+
+    fn retry_context(server: &MockServer) -> EventRootContext {
+        test_context(
+            server,
+            json!({"batch_max_wait": 20, "retry_base_backoff": 10, "retry_jitter": false}),
+        )
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_until_the_batch_is_delivered() {
+        let server = MockServer::start();
+        server.respond_once("/v1/events/batch", MockResponse::status(503));
+        let context = retry_context(&server);
+
+        context.push_event(Event::new()).await;
+        let delivered = context
+            .metrics
+            .api_responses
+            .with_label_values(&["/v1/events/batch", "200"]);
+        wait_for("the delivered batch", || delivered.get() == 1).await;
+
+        let requests = server.take_requests("/v1/events/batch");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, requests[1].body);
+        assert_eq!(context.metrics.upload_retries.get(), 1);
+        let dropped = context.metrics.events_dropped.with_label_values(&["upload_failed"]);
+        assert_eq!(dropped.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_auth_errors() {
+        let server = MockServer::start();
+        server.respond("/v1/events/batch", MockResponse::status(401));
+        let context = retry_context(&server);
+
+        context.push_event(Event::new()).await;
+        let dropped = context.metrics.events_dropped.with_label_values(&["upload_failed"]);
+        wait_for("the dropped batch", || dropped.get() == 1).await;
+
+        assert_eq!(server.request_count("/v1/events/batch"), 1);
+        assert_eq!(context.metrics.upload_retries.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn waits_for_retry_after_before_retrying() {
+        let server = MockServer::start();
+        server.respond_once(
+            "/v1/events/batch",
+            MockResponse::status(503).header("Retry-After", "1"),
+        );
+        let context = retry_context(&server);
+
+        context.push_event(Event::new()).await;
+        wait_for("the first attempt", || server.request_count("/v1/events/batch") == 1).await;
+        let first_attempt = Instant::now();
+        wait_for("the retry", || server.request_count("/v1/events/batch") == 2).await;
+
+        // Well past the 10ms backoff, allowing for the polling interval
+        assert!(first_attempt.elapsed() >= Duration::from_millis(950));
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_batches_on_auth_errors_and_retries_them_later() {
+        let server = MockServer::start();
+        server.respond_once("/v1/events/batch", MockResponse::status(401));
+        let spool_dir = TempDir::new();
+        let spool = Spool::open(spool_dir.path(), 1024, 1024).unwrap();
+        for batch in [r#"[{"n":1}]"#, r#"[{"n":2}]"#] {
+            spool.write_batch(batch.as_bytes()).unwrap();
+        }
+        drop(spool);
+
+        let context = test_context(
+            &server,
+            json!({"spool_dir": spool_dir.path(), "spool_replay_interval": 200}),
+        );
+        wait_for("the startup replay", || server.request_count("/v1/events/batch") == 1).await;
+        let spool = context.spool.as_ref().unwrap();
+        assert!(spool.has_batches());
+        let rejected = context.metrics.events_dropped.with_label_values(&["spool_rejected"]);
+        assert_eq!(rejected.get(), 0);
+
+        // The next tick replays both batches without any live upload
+        wait_for("the empty spool", || !spool.has_batches()).await;
+        let bodies: Vec<Vec<u8>> = server
+            .take_requests("/v1/events/batch")
+            .into_iter()
+            .map(|request| request.body)
+            .collect();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[1], br#"[{"n":1}]"#);
+        assert_eq!(bodies[2], br#"[{"n":2}]"#);
+    }
+
+    #[tokio::test]
+    async fn replay_drops_rejected_batches_and_sends_the_rest() {
+        let server = MockServer::start();
+        server.respond_once("/v1/events/batch", MockResponse::ok(""));
+        server.respond_once("/v1/events/batch", MockResponse::status(400));
+        let spool_dir = TempDir::new();
+        let spool = Spool::open(spool_dir.path(), 1024, 1024).unwrap();
+        for batch in [r#"[{"n":1}]"#, r#"[{"n":2},{"n":3}]"#, r#"[{"n":4}]"#] {
+            spool.write_batch(batch.as_bytes()).unwrap();
+        }
+        drop(spool);
+
+        let context = test_context(&server, json!({"spool_dir": spool_dir.path()}));
+        wait_for("the replay", || server.request_count("/v1/events/batch") == 3).await;
+        let spool = context.spool.as_ref().unwrap();
+        wait_for("the empty spool", || !spool.has_batches()).await;
+
+        let bodies: Vec<Vec<u8>> = server
+            .take_requests("/v1/events/batch")
+            .into_iter()
+            .map(|request| request.body)
+            .collect();
+        assert_eq!(bodies[2], br#"[{"n":4}]"#);
+        let rejected = context.metrics.events_dropped.with_label_values(&["spool_rejected"]);
+        assert_eq!(rejected.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn batcher_tracks_the_size_of_the_json_array() {
+        let mut batcher = Batcher::new(10, 23, 1000);
+        let event = Bytes::from_static(br#"{"n":1234}"#);
+
+        // An empty batch takes the event even when it is too big on its own
+        assert!(!batcher.would_overflow(&Bytes::from(vec![b' '; 100])));
+        batcher.handle_new_event(event.clone()).await;
+        assert!(!batcher.should_flush());
+
+        // "[" + event + "," + event + "]" is exactly 23 bytes
+        assert!(!batcher.would_overflow(&event));
+        assert!(batcher.would_overflow(&Bytes::from_static(br#"{"n":12345}"#)));
+        batcher.handle_new_event(event).await;
+        assert_eq!(batcher.buffer_bytes, format!("[{0},{0}]", r#"{"n":1234}"#).len());
+        assert!(batcher.should_flush());
+
+        batcher.reset();
+        assert!(!batcher.has_events());
+        assert!(!batcher.should_flush());
+    }
+
+    #[tokio::test]
+    async fn limits_uploads_in_flight_while_the_next_batch_fills() {
+        let server = MockServer::start();
+        server.respond_once(
+            "/v1/events/batch",
+            MockResponse::ok("").delay(Duration::from_secs(30)),
+        );
+        server.respond_once(
+            "/v1/events/batch",
+            MockResponse::ok("").delay(Duration::from_secs(1)),
+        );
+        let context = test_context(
+            &server,
+            json!({
+                "max_concurrent_uploads": 2,
+                "batch_max_size": 2,
+                "batch_max_wait": 10_000,
+            }),
+        );
+
+        for n in 0..4 {
+            context.push_event(numbered_event(n)).await;
+        }
+        wait_for("two uploads", || server.request_count("/v1/events/batch") == 2).await;
+
+        // The third batch fills while both permits are taken, then waits for one
+        for n in 4..6 {
+            context.push_event(numbered_event(n)).await;
+        }
+        wait_for("the waiting batch", || context.in_flight.lock().unwrap().len() == 3).await;
+        assert_eq!(server.request_count("/v1/events/batch"), 2);
+
+        // It is posted once the second upload finishes, while the first is still in flight
+        wait_for("the third upload", || server.request_count("/v1/events/batch") == 3).await;
+        let requests = server.take_requests("/v1/events/batch");
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&requests[2].body).unwrap();
+        let uris: Vec<&str> = events
+            .iter()
+            .map(|event| event["request"]["uri"].as_str().unwrap())
+            .collect();
+        assert_eq!(uris, vec!["/4", "/5"]);
+    }
+
+    fn numbered_event(n: usize) -> Event {
+        let mut event = Event::new();
+        event.request.uri = format!("/{}", n);
+        event
+    }
+
+    // A context whose processor is stuck behind an upload that never finishes, so the
+    // two-event queue stays full
+    async fn saturated_context(server: &MockServer, policy: &str) -> EventRootContext {
+        server.respond(
+            "/v1/events/batch",
+            MockResponse::ok("").delay(Duration::from_secs(30)),
+        );
+        let context = test_context(
+            server,
+            json!({
+                "overflow_policy": policy,
+                "queue_max_size": 2,
+                "max_concurrent_uploads": 1,
+                "batch_max_size": 1,
+            }),
+        );
+        context.push_event(numbered_event(0)).await;
+        wait_for("the first batch", || server.request_count("/v1/events/batch") == 1).await;
+        // Taken by the processor, which then waits for the upload permit
+        context.push_event(numbered_event(1)).await;
+        wait_for("the blocked upload", || context.in_flight.lock().unwrap().len() == 2).await;
+        for n in 2..4 {
+            context.push_event(numbered_event(n)).await;
+        }
+        context
+    }
+
+    async fn queued_uris(context: &EventRootContext) -> Vec<String> {
+        let mut event_receiver = context.event_receiver.lock().await;
+        let mut uris = Vec::new();
+        while let Ok(event) = event_receiver.try_recv() {
+            let event: serde_json::Value = serde_json::from_slice(&event).unwrap();
+            uris.push(event["request"]["uri"].as_str().unwrap().to_string());
+        }
+        uris
+    }
+
+    #[tokio::test]
+    async fn drop_newest_discards_new_events_without_blocking() {
+        let server = MockServer::start();
+        let context = saturated_context(&server, "drop_newest").await;
+
+        let pushes = async {
+            for n in 4..7 {
+                context.push_event(numbered_event(n)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), pushes).await.unwrap();
+
+        let dropped = context.metrics.events_dropped.with_label_values(&["drop_newest"]);
+        assert_eq!(dropped.get(), 3);
+        assert_eq!(queued_uris(&context).await, vec!["/2", "/3"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_discards_queued_events_without_blocking() {
+        let server = MockServer::start();
+        let context = saturated_context(&server, "drop_oldest").await;
+
+        let pushes = async {
+            for n in 4..7 {
+                context.push_event(numbered_event(n)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), pushes).await.unwrap();
+
+        let dropped = context.metrics.events_dropped.with_label_values(&["drop_oldest"]);
+        assert_eq!(dropped.get(), 3);
+        assert_eq!(queued_uris(&context).await, vec!["/5", "/6"]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room_in_the_queue() {
+        let server = MockServer::start();
+        let context = saturated_context(&server, "block").await;
+
+        let push = context.push_event(numbered_event(4));
+        assert!(tokio::time::timeout(Duration::from_millis(200), push).await.is_err());
+
+        for policy in ["drop_newest", "drop_oldest"] {
+            assert_eq!(context.metrics.events_dropped.with_label_values(&[policy]).get(), 0);
+        }
+        assert_eq!(queued_uris(&context).await, vec!["/2", "/3"]);
     }
 }
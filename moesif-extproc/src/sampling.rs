@@ -0,0 +1,201 @@
+use log::trace;
+use rand::Rng;
+use serde_json::Value;
+
+use crate::config::{AppConfigResponse, RegexCondition, RegexRule};
+use crate::event::Event;
+
+// Decides whether the event is logged and stamps its weight when it is
+pub fn sample_event(app_config: &AppConfigResponse, event: &mut Event) -> bool {
+    let sample_rate = get_sampling_percentage(app_config, event);
+    if !should_sample(sample_rate) {
+        trace!("Event skipped by sampling with sample rate {}", sample_rate);
+        return false;
+    }
+    event.weight = Some(get_weight(sample_rate));
+    true
+}
+
+// Precedence follows the other Moesif SDKs: regex rules, user, company, then the app default
+pub fn get_sampling_percentage(app_config: &AppConfigResponse, event: &Event) -> i32 {
+    if let Some(sample_rate) = get_regex_sample_rate(&app_config.regex_config, event) {
+        return sample_rate;
+    }
+    if let Some(sample_rate) = event
+        .user_id
+        .as_ref()
+        .and_then(|user_id| app_config.user_sample_rate.get(user_id))
+    {
+        return *sample_rate;
+    }
+    if let Some(sample_rate) = event
+        .company_id
+        .as_ref()
+        .and_then(|company_id| app_config.company_sample_rate.get(company_id))
+    {
+        return *sample_rate;
+    }
+    app_config.sample_rate
+}
+
+fn get_regex_sample_rate(regex_config: &[RegexRule], event: &Event) -> Option<i32> {
+    regex_config
+        .iter()
+        .find(|rule| matches_conditions(&rule.conditions, event))
+        .map(|rule| rule.sample_rate)
+}
+
+// All conditions must match, an empty condition list never matches
+pub fn matches_conditions(conditions: &[RegexCondition], event: &Event) -> bool {
+    !conditions.is_empty()
+        && conditions.iter().all(|condition| {
+            let field_value = match get_field_value(event, &condition.path) {
+                Some(value) => value,
+                None => return false,
+            };
+            condition
+                .regex
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&field_value))
+        })
+}
+
+pub fn get_field_value(event: &Event, path: &str) -> Option<String> {
+    match path {
+        "request.verb" => Some(event.request.verb.clone()),
+        "request.ip_address" => event.request.ip_address.clone(),
+        "request.route" => event.request.uri.split('?').next().map(|s| s.to_string()),
+        "response.status" => event
+            .response
+            .as_ref()
+            .map(|response| response.status.to_string()),
+        _ => {
+            if let Some(header) = path.strip_prefix("request.headers.") {
+                event.request.headers.get(&header.to_lowercase()).cloned()
+            } else if let Some(field) = path.strip_prefix("request.body.") {
+                match event.request.body.get(field) {
+                    Some(Value::String(value)) => Some(value.clone()),
+                    Some(value) => Some(value.to_string()),
+                    None => None,
+                }
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn should_sample(sample_rate: i32) -> bool {
+    sample_rate >= 100 || (sample_rate > 0 && rand::thread_rng().gen_range(0..100) < sample_rate)
+}
+
+fn get_weight(sample_rate: i32) -> i32 {
+    if sample_rate <= 0 || sample_rate >= 100 {
+        1
+    } else {
+        100 / sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{app_config, event};
+    use serde_json::json;
+
+    fn precedence_config() -> AppConfigResponse {
+        app_config(json!({
+            "sample_rate": 50,
+            "user_sample_rate": {"machine-user": 1},
+            "company_sample_rate": {"acme": 20},
+            "regex_config": [{
+                "conditions": [{"path": "request.route", "value": "^/health"}],
+                "sample_rate": 5,
+            }],
+        }))
+    }
+
+    #[test]
+    fn regex_rules_take_precedence_over_user_and_company() {
+        let app_config = precedence_config();
+        let event = event("/health/live?full=1", Some("machine-user"), Some("acme"));
+        assert_eq!(get_sampling_percentage(&app_config, &event), 5);
+    }
+
+    #[test]
+    fn user_rate_takes_precedence_over_company() {
+        let app_config = precedence_config();
+        let event = event("/items", Some("machine-user"), Some("acme"));
+        assert_eq!(get_sampling_percentage(&app_config, &event), 1);
+    }
+
+    #[test]
+    fn company_rate_applies_to_unlisted_users() {
+        let app_config = precedence_config();
+        let event = event("/items", Some("human"), Some("acme"));
+        assert_eq!(get_sampling_percentage(&app_config, &event), 20);
+    }
+
+    #[test]
+    fn falls_back_to_the_app_sample_rate() {
+        let app_config = precedence_config();
+        assert_eq!(get_sampling_percentage(&app_config, &event("/items", None, None)), 50);
+        assert_eq!(
+            get_sampling_percentage(&AppConfigResponse::default(), &event("/items", None, None)),
+            100
+        );
+    }
+
+    #[test]
+    fn regex_rules_require_every_condition() {
+        let app_config = app_config(json!({
+            "sample_rate": 100,
+            "regex_config": [{
+                "conditions": [
+                    {"path": "request.route", "value": "^/items$"},
+                    {"path": "request.verb", "value": "POST"},
+                ],
+                "sample_rate": 10,
+            }],
+        }));
+        assert_eq!(get_sampling_percentage(&app_config, &event("/items", None, None)), 100);
+        let mut post = event("/items", None, None);
+        post.request.verb = "POST".to_string();
+        assert_eq!(get_sampling_percentage(&app_config, &post), 10);
+    }
+
+    #[test]
+    fn invalid_regexes_never_match() {
+        let app_config = app_config(json!({
+            "sample_rate": 100,
+            "regex_config": [{
+                "conditions": [{"path": "request.route", "value": "(unclosed"}],
+                "sample_rate": 10,
+            }],
+        }));
+        assert!(app_config.regex_config[0].conditions[0].regex.is_none());
+        assert_eq!(get_sampling_percentage(&app_config, &event("(unclosed", None, None)), 100);
+    }
+
+    #[test]
+    fn weight_is_the_inverse_of_the_sample_rate() {
+        assert_eq!(get_weight(100), 1);
+        assert_eq!(get_weight(50), 2);
+        assert_eq!(get_weight(20), 5);
+        assert_eq!(get_weight(30), 3);
+        assert_eq!(get_weight(1), 100);
+        assert_eq!(get_weight(0), 1);
+        assert_eq!(get_weight(150), 1);
+    }
+
+    #[test]
+    fn sampled_events_are_weighted() {
+        let mut logged = event("/items", None, None);
+        assert!(sample_event(&app_config(json!({"sample_rate": 100})), &mut logged));
+        assert_eq!(logged.weight, Some(1));
+
+        let mut skipped = event("/items", None, None);
+        assert!(!sample_event(&app_config(json!({"sample_rate": 0})), &mut skipped));
+        assert_eq!(skipped.weight, None);
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use log::{info, trace};
+
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_SUFFIX: &str = ".ndjson";
+
+// On-disk queue of event batches, one JSON array per line, split into numbered segment files.
+// Segments are replayed oldest first and removed once every batch in them was accepted.
+pub struct Spool {
+    dir: PathBuf,
+    segment_max_bytes: u64,
+    max_bytes: u64,
+    // Sequence number of the segment new batches are appended to
+    active_segment: Mutex<u64>,
+    replaying: AtomicBool,
+    // Set when a batch is written and cleared once a replay finds no segment left,
+    // so checking for spooled batches needs no I/O
+    has_batches: AtomicBool,
+}
+
+pub struct Segment {
+    pub path: PathBuf,
+    pub batches: Vec<Bytes>,
+}
+
+impl Spool {
+    pub fn open(dir: &str, segment_max_bytes: u64, max_bytes: u64) -> io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        let segments = list_segments(&dir)?;
+        if !segments.is_empty() {
+            info!(
+                "Found {} spooled segments with {} bytes in {}",
+                segments.len(),
+                segments.iter().map(|(_, _, size)| size).sum::<u64>(),
+                dir.display()
+            );
+        }
+        // Start a fresh segment so that segments left over by a previous run are never appended to
+        let active_segment = segments.last().map(|(seq, _, _)| seq + 1).unwrap_or(0);
+        let has_batches = segments.iter().any(|(_, _, size)| *size > 0);
+        Ok(Self {
+            dir,
+            segment_max_bytes,
+            max_bytes,
+            active_segment: Mutex::new(active_segment),
+            replaying: AtomicBool::new(false),
+            has_batches: AtomicBool::new(has_batches),
+        })
+    }
+
+    pub fn write_batch(&self, batch: &[u8]) -> io::Result<()> {
+        let mut active_segment = self
+            .active_segment
+            .lock()
+            .map_err(|_| io::Error::other("spool lock poisoned"))?;
+
+        let path = segment_path(&self.dir, *active_segment);
+        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let path = if size > 0 && size + batch.len() as u64 + 1 > self.segment_max_bytes {
+            *active_segment += 1;
+            segment_path(&self.dir, *active_segment)
+        } else {
+            path
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut line = Vec::with_capacity(batch.len() + 1);
+        line.extend_from_slice(batch);
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_data()?;
+        self.has_batches.store(true, Ordering::SeqCst);
+        trace!("Spooled {} bytes to {}", batch.len(), path.display());
+
+        self.enforce_max_bytes(*active_segment)
+    }
+
+    pub fn has_batches(&self) -> bool {
+        self.has_batches.load(Ordering::SeqCst)
+    }
+
+    // Only one replay runs at a time, the guard releases it when dropped
+    pub fn start_replay(&self) -> Option<ReplayGuard<'_>> {
+        self.replaying
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| ReplayGuard { spool: self })
+    }
+
+    // Returns the oldest segment, rolling the active one first so nothing is appended to it while replaying
+    pub fn oldest_segment(&self) -> io::Result<Option<Segment>> {
+        let (seq, path) = {
+            let mut active_segment = self
+                .active_segment
+                .lock()
+                .map_err(|_| io::Error::other("spool lock poisoned"))?;
+            let oldest = list_segments(&self.dir)?
+                .into_iter()
+                .find(|(_, _, size)| *size > 0);
+            match oldest {
+                Some((seq, path, _)) => {
+                    if seq >= *active_segment {
+                        *active_segment = seq + 1;
+                    }
+                    (seq, path)
+                }
+                None => {
+                    // Cleared under the lock so a concurrent write_batch cannot be missed
+                    self.has_batches.store(false, Ordering::SeqCst);
+                    return Ok(None);
+                }
+            }
+        };
+        trace!("Reading spooled segment {}", seq);
+
+        let reader = match File::open(&path) {
+            Ok(file) => BufReader::new(file),
+            // Removed by enforce_max_bytes in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut batches = Vec::new();
+        for line in reader.split(b'\n') {
+            let line = line?;
+            if !line.is_empty() {
+                batches.push(Bytes::from(line));
+            }
+        }
+        Ok(Some(Segment { path, batches }))
+    }
+
+    pub fn complete_segment(&self, segment: &Segment) -> io::Result<()> {
+        match fs::remove_file(&segment.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Keeps the batches that could not be sent yet so they are not duplicated on the next replay
+    pub fn retain_batches(&self, segment: &Segment, remaining: &[Bytes]) -> io::Result<()> {
+        let tmp_path = segment.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        for batch in remaining {
+            file.write_all(batch)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp_path, &segment.path)
+    }
+
+    // Drops the oldest segments once the spool grows past max_bytes, the active segment is always kept
+    fn enforce_max_bytes(&self, active_segment: u64) -> io::Result<()> {
+        let segments = list_segments(&self.dir)?;
+        let mut total: u64 = segments.iter().map(|(_, _, size)| size).sum();
+        for (seq, path, size) in segments {
+            if total <= self.max_bytes || seq >= active_segment {
+                break;
+            }
+            log::warn!(
+                "Spool exceeded {} bytes, dropping oldest segment {} with {} bytes",
+                self.max_bytes,
+                path.display(),
+                size
+            );
+            fs::remove_file(&path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+pub struct ReplayGuard<'a> {
+    spool: &'a Spool,
+}
+
+impl Drop for ReplayGuard<'_> {
+    fn drop(&mut self) {
+        self.spool.replaying.store(false, Ordering::SeqCst);
+    }
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", SEGMENT_PREFIX, seq, SEGMENT_SUFFIX))
+}
+
+// Segments sorted oldest first as (sequence number, path, size in bytes)
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf, u64)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let seq = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            segments.push((seq, entry.path(), size));
+        }
+    }
+    segments.sort_by_key(|(seq, _, _)| *seq);
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn batch(n: u32) -> Bytes {
+        Bytes::from(format!("[{{\"n\":{}}}]", n))
+    }
+
+    fn segment_batches(spool: &Spool) -> Vec<Bytes> {
+        spool.oldest_segment().unwrap().map(|s| s.batches).unwrap_or_default()
+    }
+
+    #[test]
+    fn rolls_over_to_a_new_segment_when_full() {
+        let dir = TempDir::new();
+        // Each batch takes 10 bytes with its newline
+        let spool = Spool::open(dir.path(), 20, 1024).unwrap();
+        for n in 1..=3 {
+            spool.write_batch(&batch(n)).unwrap();
+        }
+
+        let segments = list_segments(Path::new(dir.path())).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].2, 20);
+        assert_eq!(segments[1].2, 10);
+
+        let segment = spool.oldest_segment().unwrap().unwrap();
+        assert_eq!(segment.batches, vec![batch(1), batch(2)]);
+        spool.complete_segment(&segment).unwrap();
+        assert_eq!(segment_batches(&spool), vec![batch(3)]);
+    }
+
+    #[test]
+    fn retain_batches_keeps_only_the_unsent_ones() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 1024, 1024).unwrap();
+        for n in 1..=3 {
+            spool.write_batch(&batch(n)).unwrap();
+        }
+
+        let segment = spool.oldest_segment().unwrap().unwrap();
+        spool.retain_batches(&segment, &segment.batches[2..]).unwrap();
+
+        assert_eq!(segment_batches(&spool), vec![batch(3)]);
+        assert!(!segment.path.with_extension("tmp").exists());
+        // Writes after the replay started go to the next segment
+        spool.write_batch(&batch(4)).unwrap();
+        assert_eq!(list_segments(Path::new(dir.path())).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn drops_the_oldest_segments_past_max_bytes() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 10, 25).unwrap();
+        for n in 1..=4 {
+            spool.write_batch(&batch(n)).unwrap();
+        }
+
+        let segments = list_segments(Path::new(dir.path())).unwrap();
+        let seqs: Vec<u64> = segments.iter().map(|(seq, _, _)| *seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+        assert_eq!(segment_batches(&spool), vec![batch(3)]);
+    }
+
+    #[test]
+    fn keeps_the_active_segment_even_past_max_bytes() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 1024, 15).unwrap();
+        for n in 1..=2 {
+            spool.write_batch(&batch(n)).unwrap();
+        }
+
+        assert_eq!(segment_batches(&spool), vec![batch(1), batch(2)]);
+    }
+
+    #[test]
+    fn replays_segments_left_over_from_a_previous_run() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 1024, 1024).unwrap();
+        spool.write_batch(&batch(1)).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(dir.path(), 1024, 1024).unwrap();
+        assert!(spool.has_batches());
+        spool.write_batch(&batch(2)).unwrap();
+
+        // The old segment is not appended to and is replayed first
+        assert_eq!(list_segments(Path::new(dir.path())).unwrap().len(), 2);
+        let segment = spool.oldest_segment().unwrap().unwrap();
+        assert_eq!(segment.batches, vec![batch(1)]);
+        spool.complete_segment(&segment).unwrap();
+        assert_eq!(segment_batches(&spool), vec![batch(2)]);
+        let segment = spool.oldest_segment().unwrap().unwrap();
+        spool.complete_segment(&segment).unwrap();
+        assert!(spool.oldest_segment().unwrap().is_none());
+        assert!(!spool.has_batches());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use opentelemetry::global;
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::EnvConfig;
+
+// Exports tracing spans over OTLP/HTTP when an endpoint is configured, spans are no-ops otherwise.
+// Has to be called from within the tokio runtime since the batch exporter runs on it.
+pub fn init_tracing(env: &EnvConfig) -> Result<(), String> {
+    let endpoint = match &env.otel_exporter_otlp_endpoint {
+        Some(endpoint) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        None => return Ok(()),
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            env.otel_service_name.clone(),
+        )])))
+        .install_batch(runtime::Tokio)
+        .map_err(|e| format!("Failed to create OTLP exporter: {}", e))?;
+
+    // Only the plugin's own spans, not the ones from hyper, h2 and tonic
+    let filter = Targets::new().with_target(env!("CARGO_CRATE_NAME"), tracing::Level::TRACE);
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer).with_filter(filter))
+        .try_init()
+        .map_err(|e| format!("Failed to install tracing subscriber: {}", e))?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    log::info!("Exporting traces to {}", endpoint);
+    Ok(())
+}
+
+// The W3C trace context sent by the gateway, e.g. the traceparent request header
+pub fn extract_context(headers: &HashMap<String, String>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(headers))
+}
+
+// Sends the spans that are still buffered
+pub async fn shutdown_tracing() {
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::grpc_service::MoesifGlooExtProcGrpcService;
+    use crate::test_utils::{
+        request_headers, response_headers, run_stream, test_env, wait_for, MockServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use prost::Message;
+    use serde_json::json;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // The only test that installs the global tracer, spans of other tests running at the
+    // same time are exported too and ignored here
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_stream_spans_under_the_gateway_trace() {
+        let server = MockServer::start();
+        let env = test_env(
+            &server.base_uri,
+            json!({"otel_exporter_otlp_endpoint": server.base_uri, "batch_max_wait": 20}),
+        );
+        init_tracing(&env).unwrap();
+        let service = MoesifGlooExtProcGrpcService::new(Config { env }).unwrap();
+        let event_context = service.event_context();
+
+        let traceparent = format!("00-{}-{}-01", TRACE_ID, PARENT_SPAN_ID);
+        run_stream(
+            service,
+            vec![
+                request_headers(&[
+                    (":method", "GET"),
+                    (":path", "/"),
+                    ("traceparent", &traceparent),
+                ]),
+                response_headers(&[(":status", "200")]),
+            ],
+        )
+        .await;
+        // The upload spans end right after the response is counted
+        let uploads = event_context
+            .metrics
+            .api_responses
+            .with_label_values(&["/v1/events/batch", "200"]);
+        wait_for("the batch upload", || uploads.get() == 1).await;
+        shutdown_tracing().await;
+
+        let mut spans = Vec::new();
+        for request in server.take_requests("/v1/traces") {
+            let export = ExportTraceServiceRequest::decode(request.body.as_slice()).unwrap();
+            for resource_spans in export.resource_spans {
+                for scope_spans in resource_spans.scope_spans {
+                    spans.extend(scope_spans.spans);
+                }
+            }
+        }
+        let traced: Vec<&str> = spans
+            .iter()
+            .filter(|span| hex(&span.trace_id) == TRACE_ID)
+            .map(|span| span.name.as_str())
+            .collect();
+        for name in ["ext_proc.stream", "ext_proc.phase", "push_event"] {
+            assert!(traced.contains(&name), "{} is not in the gateway trace: {:?}", name, traced);
+        }
+        let stream = spans.iter().find(|span| span.name == "ext_proc.stream").unwrap();
+        assert_eq!(hex(&stream.parent_span_id), PARENT_SPAN_ID);
+        for name in ["flush_buffer", "dispatch_http_request"] {
+            assert!(spans.iter().any(|span| span.name == name), "{} was not exported", name);
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Request, Response, Server};
+use serde_json::{json, Value};
+
+use envoy_ext_proc_proto::envoy::config::core::v3::{HeaderMap as ProtoHeaderMap, HeaderValue};
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient;
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::{
+    processing_request, HttpHeaders, ProcessingRequest, ProcessingResponse,
+};
+use futures_util::StreamExt;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic_health::proto::health_client::HealthClient;
+use tonic_health::proto::HealthCheckRequest;
+
+use crate::config::{AppConfigResponse, EnvConfig};
+use crate::event::Event;
+use crate::grpc_service::MoesifGlooExtProcGrpcService;
+
+// An EnvConfig with the same defaults as one read from the environment
+pub fn test_env(base_uri: &str, overrides: Value) -> EnvConfig {
+    let mut env = json!({
+        "moesif_application_id": "test-application-id",
+        "base_uri": base_uri,
+    });
+    if let (Some(env), Value::Object(overrides)) = (env.as_object_mut(), overrides) {
+        env.extend(overrides);
+    }
+    serde_json::from_value(env).expect("invalid test config")
+}
+
+// An app config as served by the API, with its regexes compiled
+pub fn app_config(config: Value) -> AppConfigResponse {
+    let mut app_config: AppConfigResponse = serde_json::from_value(config).unwrap();
+    app_config.compile_regexes();
+    app_config
+}
+
+pub fn event(uri: &str, user_id: Option<&str>, company_id: Option<&str>) -> Event {
+    let mut event = Event::new();
+    event.request.verb = "GET".to_string();
+    event.request.uri = uri.to_string();
+    event.user_id = user_id.map(str::to_string);
+    event.company_id = company_id.map(str::to_string);
+    event
+}
+
+#[derive(Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    pub fn ok(body: &str) -> Self {
+        MockResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        MockResponse {
+            status,
+            ..Self::ok("")
+        }
+    }
+
+    // Holds the response back, e.g. to keep an upload in flight
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+pub struct RecordedRequest {
+    pub method: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+// Stands in for api.moesif.net, answering each path with its configured response
+#[derive(Clone)]
+pub struct MockServer {
+    pub base_uri: String,
+    responses: Arc<Mutex<HashMap<String, MockResponse>>>,
+    // Answered first, in order, before falling back to the path's response
+    queued: Arc<Mutex<HashMap<String, VecDeque<MockResponse>>>>,
+    requests: Arc<Mutex<HashMap<String, Vec<RecordedRequest>>>>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let responses: Arc<Mutex<HashMap<String, MockResponse>>> = Default::default();
+        let queued: Arc<Mutex<HashMap<String, VecDeque<MockResponse>>>> = Default::default();
+        let requests: Arc<Mutex<HashMap<String, Vec<RecordedRequest>>>> = Default::default();
+        let service_state = (responses.clone(), queued.clone(), requests.clone());
+        let make_service = make_service_fn(move |_| {
+            let (responses, queued, requests) = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let (responses, queued, requests) =
+                        (responses.clone(), queued.clone(), requests.clone());
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let method = req.method().to_string();
+                        let headers = req.headers().clone();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+                        requests.lock().unwrap().entry(path.clone()).or_default().push(
+                            RecordedRequest {
+                                method,
+                                headers,
+                                body: body.to_vec(),
+                            },
+                        );
+                        let next = queued
+                            .lock()
+                            .unwrap()
+                            .get_mut(&path)
+                            .and_then(VecDeque::pop_front);
+                        let mock = next.unwrap_or_else(|| {
+                            responses
+                                .lock()
+                                .unwrap()
+                                .get(&path)
+                                .cloned()
+                                .unwrap_or_else(|| MockResponse::ok(""))
+                        });
+                        tokio::time::sleep(mock.delay).await;
+                        let mut response = Response::builder().status(mock.status);
+                        for (name, value) in &mock.headers {
+                            response = response.header(name.as_str(), value.as_str());
+                        }
+                        Ok::<_, Infallible>(response.body(Body::from(mock.body)).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let base_uri = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        MockServer {
+            base_uri,
+            responses,
+            queued,
+            requests,
+        }
+    }
+
+    pub fn respond(&self, path: &str, response: MockResponse) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), response);
+    }
+
+    // Answers the next request to the path that has no earlier queued response
+    pub fn respond_once(&self, path: &str, response: MockResponse) {
+        self.queued
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .push_back(response);
+    }
+
+    pub fn request_count(&self, path: &str) -> usize {
+        self.requests.lock().unwrap().get(path).map_or(0, Vec::len)
+    }
+
+    pub fn take_requests(&self, path: &str) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().remove(path).unwrap_or_default()
+    }
+}
+
+// A uniquely named directory under the system temp dir, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("moesif-extproc-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).expect("failed to create temp dir");
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().expect("temp dir is not valid UTF-8")
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Polls the condition until it holds, failing the test after a few seconds
+pub async fn wait_for(description: &str, condition: impl Fn() -> bool) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("timed out waiting for {}", description);
+}
+
+pub fn request_headers(headers: &[(&str, &str)]) -> ProcessingRequest {
+    ProcessingRequest {
+        request: Some(processing_request::Request::RequestHeaders(http_headers(headers))),
+        ..Default::default()
+    }
+}
+
+pub fn response_headers(headers: &[(&str, &str)]) -> ProcessingRequest {
+    ProcessingRequest {
+        request: Some(processing_request::Request::ResponseHeaders(http_headers(headers))),
+        ..Default::default()
+    }
+}
+
+fn http_headers(headers: &[(&str, &str)]) -> HttpHeaders {
+    HttpHeaders {
+        headers: Some(ProtoHeaderMap {
+            headers: headers
+                .iter()
+                .map(|(key, value)| HeaderValue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+        }),
+        ..Default::default()
+    }
+}
+
+// Serves the ExternalProcessor on a local port and runs one stream through it like Envoy would,
+// returning once the service closed the stream
+pub async fn run_stream(
+    service: MoesifGlooExtProcGrpcService,
+    requests: Vec<ProcessingRequest>,
+) -> Vec<ProcessingResponse> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(ExternalProcessorServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    let mut client = ExternalProcessorClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let responses = client
+        .process(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    responses.map(|response| response.unwrap()).collect().await
+}
+
+// Connects anew each time, so an answer means the listener still accepts connections
+pub async fn health_status(addr: SocketAddr) -> Option<i32> {
+    let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", addr))
+        .ok()?
+        .connect()
+        .await
+        .ok()?;
+    let mut client = HealthClient::new(channel);
+    let request = HealthCheckRequest {
+        service: String::new(),
+    };
+    Some(client.check(request).await.ok()?.into_inner().status)
+}
@@ -0,0 +1,359 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::config::EnvConfig;
+
+type TlsIncoming = ReceiverStream<Result<TlsStream<TcpStream>, std::io::Error>>;
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Holds the current server config and swaps it when the certificate files change
+#[derive(Clone)]
+pub struct TlsConfigReloader {
+    env: EnvConfig,
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsConfigReloader {
+    pub fn new(env: EnvConfig) -> Result<Self, String> {
+        let server_config = load_server_config(&env)?;
+        Ok(TlsConfigReloader {
+            env,
+            server_config: Arc::new(RwLock::new(Arc::new(server_config))),
+        })
+    }
+
+    fn current(&self) -> Option<Arc<ServerConfig>> {
+        self.server_config.read().ok().map(|config| config.clone())
+    }
+
+    pub fn spawn_reload_task(&self) {
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = reloader.files_modified();
+            let mut interval =
+                tokio::time::interval(Duration::from_millis(reloader.env.tls_reload_interval));
+            loop {
+                interval.tick().await;
+                let modified = reloader.files_modified();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                match load_server_config(&reloader.env) {
+                    Ok(server_config) => match reloader.server_config.write() {
+                        Ok(mut current) => {
+                            *current = Arc::new(server_config);
+                            info!("Reloaded TLS certificates.");
+                        }
+                        Err(e) => error!("Failed to store TLS config: {:?}", e),
+                    },
+                    // Keep serving with the previous certificates, the files may be mid-update
+                    Err(e) => error!("Failed to reload TLS certificates: {}", e),
+                }
+            }
+        });
+    }
+
+    fn files_modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            self.env.tls_cert_file.as_ref(),
+            self.env.tls_key_file.as_ref(),
+            self.env.tls_client_ca_file.as_ref(),
+        ]
+        .iter()
+        .map(|path| {
+            path.and_then(|path| fs::metadata(path).ok())
+                .and_then(|metadata| metadata.modified().ok())
+        })
+        .collect()
+    }
+
+    // Accepts TCP connections and hands over the streams that complete the TLS handshake
+    pub fn incoming(&self, listener: TcpListener) -> TlsIncoming {
+        let (tx, rx) = mpsc::channel(128);
+        let reloader = self.clone();
+        let handshake_timeout = Duration::from_millis(self.env.tls_handshake_timeout);
+        tokio::spawn(async move {
+            let mut accept_backoff = MIN_ACCEPT_BACKOFF;
+            while !tx.is_closed() {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(connection) => {
+                        accept_backoff = MIN_ACCEPT_BACKOFF;
+                        connection
+                    }
+                    Err(e) => {
+                        // Errors like EMFILE persist for a while, so retrying right away would spin
+                        error!("Failed to accept connection, retrying in {:?}: {}", accept_backoff, e);
+                        tokio::time::sleep(accept_backoff).await;
+                        accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
+                    }
+                };
+                let server_config = match reloader.current() {
+                    Some(server_config) => server_config,
+                    None => continue,
+                };
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let handshake = TlsAcceptor::from(server_config).accept(stream);
+                    match tokio::time::timeout(handshake_timeout, handshake).await {
+                        Ok(Ok(tls_stream)) => {
+                            let _ = tx.send(Ok(tls_stream)).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer_addr, e),
+                        Err(_) => debug!(
+                            "TLS handshake with {} timed out after {:?}",
+                            peer_addr, handshake_timeout
+                        ),
+                    }
+                });
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+fn load_server_config(env: &EnvConfig) -> Result<ServerConfig, String> {
+    let cert_file = env.tls_cert_file.as_deref().unwrap_or_default();
+    let key_file = env.tls_key_file.as_deref().unwrap_or_default();
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &env.tls_client_ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("Invalid CA certificate in {}: {}", ca_file, e))?;
+            }
+            if env.tls_require_client_cert {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            } else {
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(server_config)
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("Failed to read certificates from {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| format!("Failed to read private key from {}: {}", path, e))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key found in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::grpc_service::MoesifGlooExtProcGrpcService;
+    use crate::test_utils::{request_headers, test_env, MockServer, TempDir};
+    use envoy_ext_proc_proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient;
+    use envoy_ext_proc_proto::envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+    use envoy_ext_proc_proto::envoy::service::ext_proc::v3::ProcessingResponse;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa};
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use tokio_rustls::rustls::{ClientConfig, ServerName};
+    use tokio_rustls::TlsConnector;
+    use tonic::transport::{Certificate as TonicCertificate, ClientTlsConfig, Endpoint, Identity};
+
+    struct TestPki {
+        dir: TempDir,
+        ca: rcgen::Certificate,
+    }
+
+    impl TestPki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new());
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let pki = TestPki {
+                dir: TempDir::new(),
+                ca: rcgen::Certificate::from_params(params).unwrap(),
+            };
+            fs::write(pki.path("ca.pem"), pki.ca.serialize_pem().unwrap()).unwrap();
+            pki
+        }
+
+        fn path(&self, name: &str) -> String {
+            format!("{}/{}", self.dir.path(), name)
+        }
+
+        // Writes a certificate signed by the test CA and its key, returning them as PEM
+        fn issue(&self, name: &str, purpose: ExtendedKeyUsagePurpose) -> (String, String) {
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+            params.extended_key_usages = vec![purpose];
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let cert_pem = cert.serialize_pem_with_signer(&self.ca).unwrap();
+            let key_pem = cert.serialize_private_key_pem();
+            // The key first, so a reload never pairs the new certificate with the old key
+            fs::write(self.path(&format!("{}-key.pem", name)), &key_pem).unwrap();
+            fs::write(self.path(&format!("{}.pem", name)), &cert_pem).unwrap();
+            (cert_pem, key_pem)
+        }
+
+        fn env(&self, server: &MockServer, overrides: serde_json::Value) -> EnvConfig {
+            let mut env = test_env(&server.base_uri, overrides);
+            env.tls_cert_file = Some(self.path("server.pem"));
+            env.tls_key_file = Some(self.path("server-key.pem"));
+            env
+        }
+
+        fn client_tls(&self) -> ClientTlsConfig {
+            let ca_pem = fs::read(self.path("ca.pem")).unwrap();
+            ClientTlsConfig::new()
+                .ca_certificate(TonicCertificate::from_pem(ca_pem))
+                .domain_name("localhost")
+        }
+    }
+
+    async fn serve(reloader: &TlsConfigReloader) -> SocketAddr {
+        let env = reloader.env.clone();
+        let service = MoesifGlooExtProcGrpcService::new(Config { env }).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(service))
+                .serve_with_incoming(reloader.incoming(listener)),
+        );
+        addr
+    }
+
+    // Runs a single request headers message through the ext_proc server
+    async fn process(
+        addr: SocketAddr,
+        tls: ClientTlsConfig,
+    ) -> Result<Vec<ProcessingResponse>, Box<dyn std::error::Error>> {
+        let channel = Endpoint::from_shared(format!("https://{}", addr))?
+            .tls_config(tls)?
+            .connect()
+            .await?;
+        let mut responses = ExternalProcessorClient::new(channel)
+            .process(tokio_stream::iter(vec![request_headers(&[
+                (":method", "GET"),
+                (":path", "/"),
+            ])]))
+            .await?
+            .into_inner();
+        let mut received = Vec::new();
+        while let Some(response) = responses.message().await? {
+            received.push(response);
+        }
+        Ok(received)
+    }
+
+    // The DER certificate the server presents in a fresh handshake
+    async fn presented_certificate(pki: &TestPki, addr: SocketAddr) -> Option<Vec<u8>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&load_certs(&pki.path("ca.pem")).unwrap()[0]).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.ok()?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let tls_stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .ok()?;
+        let (_, session) = tls_stream.get_ref();
+        session.peer_certificates().map(|certs| certs[0].0.clone())
+    }
+
+    #[tokio::test]
+    async fn serves_ext_proc_over_tls() {
+        let pki = TestPki::new();
+        pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let server = MockServer::start();
+        let reloader = TlsConfigReloader::new(pki.env(&server, json!({}))).unwrap();
+        let addr = serve(&reloader).await;
+
+        let responses = process(addr, pki.client_tls()).await.unwrap();
+        assert_eq!(responses.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_anonymous_clients_when_client_certs_are_required() {
+        let pki = TestPki::new();
+        pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let (client_cert, client_key) = pki.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        let server = MockServer::start();
+        let mut env = pki.env(&server, json!({"tls_require_client_cert": true}));
+        env.tls_client_ca_file = Some(pki.path("ca.pem"));
+        let reloader = TlsConfigReloader::new(env).unwrap();
+        let addr = serve(&reloader).await;
+
+        assert!(process(addr, pki.client_tls()).await.is_err());
+        let identity = Identity::from_pem(client_cert, client_key);
+        let responses = process(addr, pki.client_tls().identity(identity)).await.unwrap();
+        assert_eq!(responses.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reloads_rewritten_certificates_without_a_restart() {
+        let pki = TestPki::new();
+        pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let first = load_certs(&pki.path("server.pem")).unwrap()[0].0.clone();
+        let server = MockServer::start();
+        let env = pki.env(&server, json!({"tls_reload_interval": 20}));
+        let reloader = TlsConfigReloader::new(env).unwrap();
+        reloader.spawn_reload_task();
+        let addr = serve(&reloader).await;
+        assert_eq!(presented_certificate(&pki, addr).await, Some(first.clone()));
+
+        pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let second = load_certs(&pki.path("server.pem")).unwrap()[0].0.clone();
+        assert_ne!(first, second);
+        for _ in 0..200 {
+            if presented_certificate(&pki, addr).await.as_ref() == Some(&second) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!("timed out waiting for the reloaded certificate");
+    }
+}
@@ -1,9 +1,13 @@
 use crate::config::Config;
-use crate::event::Event;
+use crate::logging;
+use std::borrow::Cow;
 use reqwest::header::HeaderMap as ReqwestHeaderMap;
 
 use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use log::LevelFilter;
+use std::io::Write;
 
 type Headers = Vec<(String, String)>;
 
@@ -16,9 +20,14 @@ pub fn generate_curl_command(
 ) -> String {
     let mut curl_cmd = format!("curl -v -X {} '{}'", method, url);
 
-    // Add headers to the curl command
+    // Add headers to the curl command, masking sensitive ones like the application id
     for (key, value) in headers {
         let header_value = value.to_str().unwrap_or("");
+        let header_value = if value.is_sensitive() {
+            mask_secret(header_value)
+        } else {
+            header_value.to_string()
+        };
         curl_cmd.push_str(&format!(" -H '{}: {}'", key, header_value));
     }
 
@@ -31,6 +40,31 @@ pub fn generate_curl_command(
     curl_cmd
 }
 
+pub fn gzip_compress(body: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::new(level));
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+// Bodies only show up in logs when log_bodies is enabled
+pub fn loggable_body(body: &[u8], log_bodies: bool) -> Cow<'_, str> {
+    if log_bodies {
+        String::from_utf8_lossy(body)
+    } else {
+        Cow::Owned(format!("<{} bytes omitted>", body.len()))
+    }
+}
+
+// Keeps the last 4 characters so the value can still be told apart from others
+pub fn mask_secret(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 8 {
+        return "****".to_string();
+    }
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("****{}", suffix)
+}
+
 pub fn get_header(headers: &Headers, name: &str) -> Option<String> {
     headers
         .iter()
@@ -39,35 +73,27 @@ pub fn get_header(headers: &Headers, name: &str) -> Option<String> {
 }
 
 pub fn set_and_display_log_level(config: &Config) {
-    // Check if RUST_LOG is set
-    if let Some(rust_log) = &config.env.rust_log {
-        match rust_log.to_lowercase().as_str() {
-            "trace" => log::set_max_level(LevelFilter::Trace),
-            "debug" => log::set_max_level(LevelFilter::Debug),
-            "info" => log::set_max_level(LevelFilter::Info),
-            "warn" => log::set_max_level(LevelFilter::Warn),
-            "error" => log::set_max_level(LevelFilter::Error),
-            _ => {
-                // If RUST_LOG is set to an invalid value, fall back to default logic
-                set_level_based_on_debug(config);
-            }
-        }
-    } else {
-        // If RUST_LOG is not set, use the DEBUG environment variable logic
-        set_level_based_on_debug(config);
+    // RUST_LOG sets the level when it has a default level, per-target directives are applied by the logger
+    match config.env.rust_log.as_deref().and_then(logging::default_level) {
+        Some(level) => log::set_max_level(level),
+        // If RUST_LOG is not set or has no default level, use the DEBUG environment variable logic
+        None => set_level_based_on_debug(config),
     }
 
     log::info!("Configuration: {:?}", config);
 
-    // Display the current log level
-    match log::max_level() {
-        LevelFilter::Error => println!("Logging level set to: ERROR"),
-        LevelFilter::Warn => println!("Logging level set to: WARN"),
-        LevelFilter::Info => println!("Logging level set to: INFO"),
-        LevelFilter::Debug => println!("Logging level set to: DEBUG"),
-        LevelFilter::Trace => println!("Logging level set to: TRACE"),
-        LevelFilter::Off => println!("Logging is turned OFF"),
-    }
+    // Display the current log level, bypassing the level so that it always shows up
+    let message = match log::max_level() {
+        LevelFilter::Off => "Logging is turned OFF".to_string(),
+        level => format!("Logging level set to: {}", level),
+    };
+    log::logger().log(
+        &log::Record::builder()
+            .args(format_args!("{}", message))
+            .level(log::Level::Info)
+            .target(module_path!())
+            .build(),
+    );
 }
 
 fn set_level_based_on_debug(config: &Config) {